@@ -0,0 +1,66 @@
+/*
+ * a LeNet-5 style convnet on mnist:
+ *     conv 5x5 (6) -> maxpool 2x2 -> conv 5x5 (16) -> maxpool 2x2
+ *         -> dense 120 -> dense 84 -> dense 10
+ * run it from the repo root with the idx files in ./res, like main. it
+ * trains for 10 epochs and prints the final test error. the aim is under
+ * 1%, but that hasn't been measured yet: the machine this was written on
+ * had no copy of mnist. put the number here once it has been.
+ */
+extern crate mnist;
+use mnist::idx::read_idx;
+use mnist::nets::*;
+
+fn main()
+{
+    // pixels scaled to [0, 1]
+    let scale = |v: Vec<mnist::Matrix<f32>>| -> Vec<_> {
+        v.into_iter().map(|x| x.map(|e| e / 255.0)).collect()
+    };
+    let xs = scale(read_idx("./res/train-images.idx3-ubyte", 60_000));
+    let ys = read_idx("./res/train-labels.idx1-ubyte", 60_000);
+    let tx = scale(read_idx("./res/t10k-images.idx3-ubyte", 10_000));
+    let ty = read_idx("./res/t10k-labels.idx1-ubyte", 10_000);
+    let test_set: Vec<_> = tx.into_iter().zip(ty).collect();
+
+    let seed = 5;
+    let mut rng = mnist::rng::seeded(seed);
+    let af = Act::Relu;
+
+    let c1 = Conv2d::new_rand(af, (1, 28, 28), 6, 5, 1, 2, &mut rng);
+    let p1 = MaxPool2d::new(c1.out_shape(), 2, 2);
+    let c2 = Conv2d::new_rand(af, p1.out_shape(), 16, 5, 1, 0, &mut rng);
+    let p2 = MaxPool2d::new(c2.out_shape(), 2, 2);
+    let flat = Flatten::new(p2.out_shape());
+    let (c, h, w) = p2.out_shape();
+
+    // much lighter than the default l2, which is tuned for main's net
+    let dense = |into, out, rng: &mut mnist::rng::StdRng| {
+        let mut layer = Layer::new_rand(af, into, out, rng);
+        layer.set_regularization(Regularization::l2(5e-4));
+        Box::new(layer)
+    };
+
+    let mut net = FFNet::new(
+            Sequential::new(vec![
+                Box::new(c1),
+                Box::new(p1),
+                Box::new(c2),
+                Box::new(p2),
+                Box::new(flat),
+                dense(c * h * w, 120, &mut rng),
+                dense(120, 84, &mut rng),
+                dense(84, 10, &mut rng),
+            ]),
+            Box::new(Adam::new()),
+            test_set,
+            4);
+    net.set_schedule(Box::new(StepDecay::new(2000, 0.7)));
+    net.set_batched(true);
+    net.set_seed(seed);
+    // 10 epochs of 64 sample batches
+    net.set_max_batches(10 * 60_000 / 64);
+    net.train(64, 0.001, (xs, ys));
+
+    println!("final test error {:.2}%", 100.0 * (1.0 - net.accuracy()));
+}
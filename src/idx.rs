@@ -0,0 +1,77 @@
+use Matrix;
+
+use std::fs::File;
+use std::io::{BufReader, Bytes, Read};
+
+/*
+ * reads up to num_vals entries of an mnist idx file. images come back as
+ * (rows * cols, 1) columns of raw 0-255 pixel values and labels as one hot
+ * (10, 1) columns.
+ */
+pub fn read_idx(fname: &str, num_vals: usize) -> Vec<Matrix<f32>>
+{
+    let f = File::open(fname).unwrap();
+    let mut bytes = BufReader::new(f).bytes();
+
+    let magic_num = read_int(&mut bytes);
+    let dim = magic_num % (1 << 3);
+    let len = read_int(&mut bytes);
+
+    return match dim {
+        1 => {
+            let mut r = Vec::with_capacity(len);
+            for res in bytes.by_ref() {
+                if let Ok(b) = res {
+                    let mut v = vec![0.0; 10];
+                    v[b as usize] = 1.0;
+                    r.push(Matrix::with_vec((10, 1), v));
+                    if r.len() >= num_vals {
+                        break;
+                    }
+                } else {
+                    panic!("some error with reading the byte from the file");
+                }
+            }
+            r
+        }
+        3 => {
+            let (m, n) = (read_int(&mut bytes), read_int(&mut bytes));
+            let mut r = Vec::with_capacity(len);
+
+            let mut i = 0;
+            let mut v = Vec::with_capacity(m * n);
+
+            for res in bytes.by_ref() {
+                if i >= m * n {
+                    i = 0;
+                    r.push(Matrix::with_vec((m * n, 1), v));
+                    v = Vec::with_capacity(m * n);
+                    if r.len() >= num_vals {
+                        break;
+                    }
+                }
+                if let Ok(b) = res {
+                    v.push(b as f32);
+                } else {
+                    panic!("some error with reading the byte from the file");
+                }
+                i += 1;
+            }
+            // the last image only fills up once the bytes have run out
+            if v.len() == m * n && r.len() < num_vals {
+                r.push(Matrix::with_vec((m * n, 1), v));
+            }
+            r
+        }
+        _ => panic!("some problem with the dimension read from the idx file"),
+    };
+
+    fn read_int(it: &mut Bytes<BufReader<File>>) -> usize
+    {
+        let mut n: usize = 0;
+        for i in 0..4 {
+            n += (it.next().unwrap().unwrap() as usize) << ((3 - i) * 8);
+        }
+        n
+    }
+}
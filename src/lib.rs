@@ -1,12 +1,25 @@
 pub use matrix::Matrix;
 pub mod matrix;
+
+pub use sparse::SparseMatrix;
+pub mod sparse;
+
+pub use tensor::Tensor;
+pub mod tensor;
+
+pub mod idx;
+pub mod rng;
 pub mod nets;
 
+#[allow(dead_code)]
 mod thread_pool;
 #[cfg(test)]
 mod tests
 {
     use matrix;
+    use SparseMatrix;
+    use Tensor;
+    use rng;
     #[test]
     fn it_works()
     {
@@ -16,8 +29,1513 @@ mod tests
     #[test]
     fn rusty_trombone()
     {
-        let mut a = matrix::Matrix::with_vec((10, 1), vec![2.0; 10]);
+        let a = matrix::Matrix::with_vec((10, 1), vec![2.0; 10]);
 
         println!("{:?}", a);
     }
+
+    #[test]
+    fn display_aligns_and_elides()
+    {
+        let a = matrix::Matrix::with_vec((2, 2), vec![1.0, -20.5, 3.0, 4.0]);
+        assert_eq!(format!("{:.1}", a),
+                   "Matrix 2x2\n[[1.0 -20.5]\n [3.0   4.0]]\n");
+
+        let big = matrix::Matrix::new_const((100, 100), 0.0);
+        let s = format!("{}", big);
+        assert_eq!(s.lines().count(), 1 + 9);
+        assert!(s.lines().all(|l| l.len() < 100));
+    }
+
+    #[test]
+    fn render_shades_by_intensity()
+    {
+        let img = matrix::Matrix::with_vec((4, 1), vec![0.0, 255.0, 127.5, 0.0]);
+        assert_eq!(img.render(2, &[' ', '.', '#']), " #\n. \n");
+    }
+
+    fn assert_close(a: &matrix::Matrix<f32>, b: &matrix::Matrix<f32>)
+    {
+        assert_eq!(a.dim, b.dim);
+        for (x, y) in a.a.iter().zip(b.a.iter()) {
+            assert!((x - y).abs() < 1e-4, "{} != {}", x, y);
+        }
+    }
+
+    #[test]
+    fn sparse_products_match_dense()
+    {
+        let mut r = rng::seeded(1);
+        let d = matrix::Matrix::new_rand((3, 6), -1.0, 1.0, &mut r);
+        let s = matrix::Matrix::with_vec((6, 2),
+                                         vec![0.0, 1.5, 0.0, 0.0, -2.0, 0.0,
+                                              0.0, 0.0, 0.0, 3.0, 0.0, 0.0]);
+        let sp = SparseMatrix::from_dense(&s);
+        assert_eq!(sp.nnz(), 3);
+        assert_close(&sp.to_dense(), &s);
+
+        assert_close(&d.mul_sparse(&sp), &(&d * &s));
+        let e = matrix::Matrix::new_rand((2, 4), -1.0, 1.0, &mut r);
+        assert_close(&(&sp * &e), &(&s * &e));
+        assert_close(&d.mul_tr_sparse(&SparseMatrix::from_dense(&s.t())),
+                     &d.mul_tr(&s.t()));
+    }
+
+    #[test]
+    fn sparse_layer_matches_dense()
+    {
+        use nets::*;
+
+        let mut r = rng::seeded(2);
+        let mut layer = Layer::new_rand(RELU {}, 6, 3, &mut r);
+        let x = matrix::Matrix::with_vec((6, 1),
+                                         vec![0.0, 4.0, 0.0, 0.0, 9.0, 0.0]);
+        let xs = SparseMatrix::from_dense(&x);
+        assert_close(&layer.prop_sparse(&xs, &Reference),
+                     &layer.prop(x.clone(), &Reference));
+
+        let g = matrix::Matrix::new_rand((3, 1), -1.0, 1.0, &mut r);
+        layer.backprop(g.clone(), x, &Reference);
+        let dense = layer.gradients();
+        layer.zero_grad();
+        layer.backprop_sparse(g, &xs, &Reference);
+        assert_close(&layer.gradients()[0], &dense[0]);
+    }
+
+    #[test]
+    fn tensor_permute_reshape_broadcast()
+    {
+        let t = Tensor::new(vec![2, 3, 4], (0..24).map(|e| e as f32).collect());
+        assert_eq!(t[&[1, 2, 3][..]], 23.0);
+
+        let p = t.permute(&[2, 0, 1]);
+        assert_eq!(p.shape, vec![4, 2, 3]);
+        assert_eq!(p[&[3, 1, 2][..]], 23.0);
+        assert_eq!(p.reshape(vec![4, 6]).to_vec()[..6],
+                   [0.0, 4.0, 8.0, 12.0, 16.0, 20.0]);
+
+        let row = Tensor::new(vec![4], vec![1.0, 2.0, 3.0, 4.0]);
+        let col = Tensor::new(vec![3, 1], vec![10.0, 20.0, 30.0]);
+        let s = &row + &col;
+        assert_eq!(s.shape, vec![3, 4]);
+        assert_eq!(s[&[2, 1][..]], 32.0);
+
+        let mut r = rng::seeded(3);
+        let m = matrix::Matrix::new_rand((3, 5), -1.0, 1.0, &mut r);
+        let back = Tensor::from_matrix(&m).permute(&[1, 0]).to_matrix();
+        assert_close(&back, &m.t());
+    }
+
+    #[test]
+    fn same_seed_same_numbers()
+    {
+        let a = matrix::Matrix::new_rand((4, 4), -1.0, 1.0, &mut rng::seeded(7));
+        let b = matrix::Matrix::new_rand((4, 4), -1.0, 1.0, &mut rng::seeded(7));
+        assert_eq!(a.a, b.a);
+
+        let c = matrix::Matrix::new_rand((4, 4), -1.0, 1.0,
+                                         &mut rng::stream(7, &[0, 1]));
+        assert!(a.a != c.a);
+    }
+
+    #[test]
+    fn functional_matrix_api()
+    {
+        use matrix::{Axis, Matrix};
+
+        let m = Matrix::from_fn((2, 3), |i, j| (i * 3 + j) as f32);
+        assert_eq!(m.a, vec![0.0, 1.0, 2.0, 3.0, 4.0, 5.0]);
+        assert_eq!(m.map(|e| e * 2.0).a[5], 10.0);
+        assert_eq!(m.rows().map(|r| r.iter().sum()).collect::<Vec<f32>>(),
+                   vec![3.0, 12.0]);
+        assert_eq!(m.cols().map(|c| c.cloned().sum()).collect::<Vec<f32>>(),
+                   vec![3.0, 5.0, 7.0]);
+
+        let h = Matrix::hstack(&[&m, &m.col(2)]);
+        assert_eq!(h.dim, (2, 4));
+        assert_eq!(h.row(1).a, vec![3.0, 4.0, 5.0, 5.0]);
+        let (l, r) = h.split(3, Axis::Cols);
+        assert_eq!(l.a, m.a);
+        assert_eq!(r.a, vec![2.0, 5.0]);
+
+        let v = Matrix::vstack(&[&m, &m.row(0)]);
+        assert_eq!(v.dim, (3, 3));
+        let (top, bottom) = v.split(2, Axis::Rows);
+        assert_eq!(top.a, m.a);
+        assert_eq!(bottom.reshape((3, 1)).a, vec![0.0, 1.0, 2.0]);
+
+        // empty pieces, like the right half of a split at the last column
+        let (_, none) = m.split(3, Axis::Cols);
+        assert_eq!(none.dim, (2, 0));
+        assert_eq!(Matrix::hstack(&[&none, &m, &none]).a, m.a);
+        assert_eq!(Matrix::vstack(&[&none, &none]).dim, (4, 0));
+        assert_eq!(none.rows().map(|r| r.len()).collect::<Vec<_>>(), [0, 0]);
+        assert_eq!(none.cols().count(), 0);
+        let flat: Matrix<f32> = Matrix::with_vec((0, 3), Vec::new());
+        assert_eq!(flat.rows().count(), 0);
+        assert_eq!(flat.cols().map(|c| c.count()).collect::<Vec<_>>(),
+                   [0, 0, 0]);
+
+        let mut z = m.zip_with(&m, |x, y| x - y);
+        z.map_inplace(|e| e + 1.0);
+        assert!(z.a.iter().all(|e| *e == 1.0));
+    }
+
+    #[test]
+    fn int8_gemm_tracks_float()
+    {
+        use nets::quant::{gemm_i8, QMatrix};
+
+        assert_eq!(gemm_i8(&[1, -2, 3, 4], &[5, 6, -7, 8], 2, 2, 2),
+                   vec![19, -10, -13, 50]);
+
+        let mut r = rng::seeded(4);
+        let w = matrix::Matrix::new_rand((5, 30), -0.3, 0.3, &mut r);
+        let q = QMatrix::quantize_rows(&w).dequantize();
+        for (a, b) in w.a.iter().zip(q.a.iter()) {
+            assert!((a - b).abs() <= 0.6 / 255.0, "{} vs {}", a, b);
+        }
+    }
+
+    #[test]
+    fn quantized_net_agrees_with_float()
+    {
+        use nets::*;
+
+        let mut r = rng::seeded(5);
+        let xs: Vec<_> = (0..50)
+            .map(|_| matrix::Matrix::new_rand((20, 1), -1.0, 1.0, &mut r))
+            .collect();
+        let layers = |r: &mut rng::StdRng| -> Sequential {
+            Sequential::new(vec![
+                Box::new(Layer::new_init(Act::Relu,
+                                         20,
+                                         10,
+                                         Init::XavierNormal,
+                                         Init::Constant(0.0),
+                                         r)),
+                Box::new(Layer::new_init(Act::Gelu,
+                                         10,
+                                         4,
+                                         Init::XavierNormal,
+                                         Init::Constant(0.0),
+                                         r)),
+            ])
+        };
+
+        // label each sample with the float net's own guess, so its accuracy
+        // is 1 and the quantized net's is how often it gets the same guess
+        let probe = FFNet::new(layers(&mut rng::seeded(6)),
+                               Box::new(Sgd),
+                               Vec::new(),
+                               1);
+        let test_set: Vec<_> = xs.iter()
+            .map(|x| {
+                let mut y = matrix::Matrix::new_const((4, 1), 0.0);
+                y.a[argmax(&probe.eval(x))] = 1.0;
+                (x.clone(), y)
+            })
+            .collect();
+        let classes: Vec<_> = test_set.iter().map(|t| argmax(&t.1)).collect();
+        assert!(classes.iter().any(|c| *c != classes[0]),
+                "the labels should differ: {:?}",
+                classes);
+
+        let net = FFNet::new(layers(&mut rng::seeded(6)),
+                             Box::new(Sgd),
+                             test_set,
+                             1);
+        let q = QuantizedNet::calibrate(&net, &xs[..20]).unwrap();
+
+        for x in &xs {
+            let (a, b) = (net.eval(x), q.eval(x));
+            for (e, f) in a.a.iter().zip(b.a.iter()) {
+                assert!((e - f).abs() < 0.02, "{} vs {}", e, f);
+            }
+        }
+        let report = QuantReport::new(&net, &q);
+        assert_eq!(report.float_accuracy, 1.0);
+        assert_eq!(report.quant_accuracy, report.agreement);
+        assert!(report.quant_accuracy > 0.9);
+
+        // anything but dense layers is an error, not a panic
+        let mut r = rng::seeded(6);
+        let mixed = FFNet::new(Sequential::new(vec![
+                                   Box::new(LayerNorm::new(20)),
+                                   Box::new(Layer::new_rand(Act::Relu,
+                                                            20,
+                                                            4,
+                                                            &mut r)),
+                               ]),
+                               Box::new(Sgd),
+                               Vec::new(),
+                               1);
+        let err = QuantizedNet::calibrate(&mixed, &xs[..20]).err().unwrap();
+        assert_eq!(err,
+                   Unquantizable { index: 0, module: "layernorm 20".into() });
+    }
+
+    #[test]
+    fn batched_layer_matches_per_sample()
+    {
+        use matrix::Matrix;
+        use nets::*;
+
+        let mut r = rng::seeded(6);
+        let mut layer = Layer::new_rand(RELU {}, 5, 3, &mut r);
+        let xs: Vec<_> = (0..4)
+            .map(|_| Matrix::new_rand((5, 1), -1.0, 1.0, &mut r))
+            .collect();
+        let gs: Vec<_> = (0..4)
+            .map(|_| Matrix::new_rand((3, 1), -1.0, 1.0, &mut r))
+            .collect();
+        let x = Matrix::hstack(&xs.iter().collect::<Vec<_>>());
+        let g = Matrix::hstack(&gs.iter().collect::<Vec<_>>());
+
+        let out = layer.prop(x.clone(), &Reference);
+        for (j, xj) in xs.iter().enumerate() {
+            assert_close(&out.col(j), &layer.prop(xj.clone(), &Reference));
+        }
+
+        let mut in_grads = Vec::new();
+        for (xj, gj) in xs.iter().zip(gs.iter()) {
+            in_grads.push(layer.backprop(gj.clone(), xj.clone(), &Reference));
+        }
+        let single = layer.gradients();
+        layer.zero_grad();
+        let in_grad = layer.backprop(g, x, &Reference);
+
+        let batch = layer.gradients();
+        assert_close(&batch[0], &single[0]);
+        assert_close(&batch[1], &single[1]);
+        let in_grads: Vec<_> = in_grads.iter().collect();
+        assert_close(&in_grad, &Matrix::hstack(&in_grads));
+
+        let p = Softmax::new().f(out);
+        for s in p.cols().map(|c| c.cloned().sum::<f32>()) {
+            assert!((s - 1.0).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn threaded_backend_matches_reference()
+    {
+        use matrix::Matrix;
+        use nets::*;
+
+        let mut r = rng::seeded(8);
+        let a = Matrix::new_rand((13, 7), -1.0, 1.0, &mut r);
+        let b = Matrix::new_rand((7, 5), -1.0, 1.0, &mut r);
+        let c = Matrix::new_rand((13, 5), -1.0, 1.0, &mut r);
+        let d = Matrix::new_rand((9, 7), -1.0, 1.0, &mut r);
+
+        for threads in 1..5 {
+            let t = Threaded::new(threads);
+            assert_close(&t.mul(&a, &b), &Reference.mul(&a, &b));
+            assert_close(&t.mul_tr(&a, &d), &Reference.mul_tr(&a, &d));
+            assert_close(&t.mul_tl(&a, &c), &Reference.mul_tl(&a, &c));
+        }
+
+        let layer = Layer::new_rand(RELU {}, 7, 13, &mut r);
+        assert_close(&layer.prop(b.clone(), &Threaded::new(3)),
+                     &layer.prop(b, &Reference));
+    }
+
+    #[test]
+    fn tape_matches_hand_written_backprop()
+    {
+        use matrix::Matrix;
+        use nets::op::Tape;
+        use nets::*;
+
+        let mut r = rng::seeded(9);
+        let layer = Layer::new_rand(RELU {}, 6, 4, &mut r);
+        let x = Matrix::new_rand((6, 3), -1.0, 1.0, &mut r);
+        let y = Matrix::from_fn((4, 3), |i, j| (i == j) as u8 as f32);
+
+        let mut t = Tape::new();
+        let (w, b, xv) = (t.var(layer.w.clone()),
+                          t.var(layer.b().clone()),
+                          t.var(x.clone()));
+        let fx = t.activation(xv, &RELU {});
+        let z = t.matmul(w, fx);
+        let z = t.add(z, b);
+        let loss = t.softmax_cross_entropy(z, &y);
+        let grads = t.backward(loss);
+
+        // the layer sums over the batch and adds 0.09 * w of weight decay
+        let out = layer.prop(x.clone(), &Reference);
+        let g = &Softmax::new().f(out) - &y;
+        let dx = layer.backprop(g, x, &Reference);
+        let buf = layer.gradients();
+
+        let n = 3.0;
+        let dw = &(grads.get(w).unwrap() * n) + &(&layer.w * (0.09 * n));
+        assert_close(&dw, &buf[0]);
+        assert_close(&(grads.get(b).unwrap() * n), &buf[1]);
+        assert_close(&(grads.get(xv).unwrap() * n), &dx);
+
+        // sum(reshape(a * b)) has gradient b wrt a
+        let mut t = Tape::new();
+        let a = t.var(Matrix::new_rand((2, 3), -1.0, 1.0, &mut r));
+        let c = t.var(Matrix::new_rand((2, 3), -1.0, 1.0, &mut r));
+        let h = t.hadamard(a, c);
+        let h = t.reshape(h, (3, 2));
+        let d = t.sub(h, h);
+        let s = t.sum(h);
+        assert!(t.backward(d).get(a).unwrap().a.iter().all(|e| *e == 0.0));
+        assert_close(t.backward(s).get(a).unwrap(), t.value(c));
+    }
+
+    // two blobs, class 0 around -1 and class 1 around +1.
+    fn blobs(n: usize, dim: usize, r: &mut rng::StdRng)
+             -> Vec<(matrix::Matrix<f32>, matrix::Matrix<f32>)>
+    {
+        use matrix::Matrix;
+
+        (0..n).map(|i| {
+                  let c = i % 2;
+                  let centre = if c == 0 { -1.0 } else { 1.0 };
+                  let x = Matrix::new_rand((dim, 1), -0.5, 0.5, r)
+                      .map(|e| e + centre);
+                  let y = Matrix::from_fn((2, 1), |k, _| (k == c) as u8 as f32);
+                  (x, y)
+              })
+              .collect()
+    }
+
+    #[test]
+    fn mixed_sequential_trains()
+    {
+        use nets::*;
+
+        let mut r = rng::seeded(10);
+        let data = blobs(64, 4, &mut r);
+        let layers: Vec<Box<dyn Module>> =
+            vec![Box::new(Layer::new_rand(ATan {}, 4, 8, &mut r)),
+                 Box::new(Layer::new_rand(RELU {}, 8, 2, &mut r))];
+        let mut net = FFNet::new(Sequential::new(layers),
+                                 Box::new(Sgd),
+                                 data.clone(),
+                                 2);
+        assert_eq!(net.layers.modules()[0].name(), "dense 4x8");
+
+        for _ in 0..50 {
+            net.update_with_batch(data.iter().map(|(x, y)| (x, y)).collect());
+            assert!(net.layers.modules()[1].gradients()[0]
+                       .a
+                       .iter()
+                       .any(|e| *e != 0.0));
+            net.update_params(data.len(), 0.1);
+            net.zero_grad();
+        }
+        assert!(net.layers.modules()[0].gradients()[0]
+                   .a
+                   .iter()
+                   .all(|e| *e == 0.0));
+        assert!(net.accuracy() > 0.95);
+    }
+
+    #[test]
+    fn activation_derivatives_match_finite_differences()
+    {
+        use nets::*;
+
+        let h = 1e-3;
+        for act in Act::all() {
+            for &x in &[-3.0, -1.2, -0.3, 0.2, 0.9, 2.5] {
+                let numeric = (act.f(x + h) - act.f(x - h)) / (2.0 * h);
+                let analytic = act.df(x);
+                assert!((numeric - analytic).abs()
+                            < 2e-3 * (1.0 + analytic.abs()),
+                        "{} at {}: numeric {} analytic {}",
+                        act,
+                        x,
+                        numeric,
+                        analytic);
+            }
+            assert_eq!(act.to_string().parse::<Act>(), Ok(act));
+        }
+
+        assert_eq!(Act::Relu.df(0.0), 0.0);
+        assert_eq!(Act::LeakyRelu(0.1).df(0.0), 0.1);
+        assert_eq!("elu".parse::<Act>(), Ok(Act::Elu(1.0)));
+        assert!("nope".parse::<Act>().is_err());
+        assert!(Softplus {}.f(100.0).is_finite());
+    }
+
+    #[test]
+    fn prelu_slopes_get_gradients()
+    {
+        use nets::*;
+
+        let mut r = rng::seeded(11);
+        let be = Reference;
+        let mut layer = Layer::new_rand(PReLU::new(4, 0.25), 4, 3, &mut r);
+        let x = matrix::Matrix::new_rand((4, 5), -1.0, 1.0, &mut r);
+        let g = matrix::Matrix::new_rand((3, 5), -1.0, 1.0, &mut r);
+        assert!(!layer.sparse_ok());
+
+        // loss = sum(g .* prop(x)), so d loss / d out is just g
+        let loss = |l: &Layer<PReLU>| -> Number {
+            l.prop(x.clone(), &be).h_prod(&g).a.iter().sum()
+        };
+
+        layer.backprop(g.clone(), x.clone(), &be);
+        let analytic = layer.gradients()[2].clone();
+        assert_eq!(analytic.dim, (4, 1));
+
+        let h = 1e-2;
+        for c in 0..4 {
+            let base = layer.activation().alpha.a[c];
+            layer.params_and_grads()[2].0.a[c] = base + h;
+            let up = loss(&layer);
+            layer.params_and_grads()[2].0.a[c] = base - h;
+            let down = loss(&layer);
+            layer.params_and_grads()[2].0.a[c] = base;
+
+            let numeric = (up - down) / (2.0 * h);
+            assert!((numeric - analytic.a[c]).abs() < 1e-3,
+                    "channel {}: numeric {} analytic {}",
+                    c,
+                    numeric,
+                    analytic.a[c]);
+        }
+
+        layer.zero_grad();
+        assert!(layer.gradients()[2].a.iter().all(|e| *e == 0.0));
+
+        // one shared slope works for any width
+        Layer::new_rand(PReLU::new(1, 0.25), 4, 3, &mut r);
+    }
+
+    #[test]
+    #[should_panic(expected = "activation has 3 params for 4 inputs")]
+    fn prelu_slopes_match_the_inputs()
+    {
+        use nets::*;
+
+        Layer::new_rand(PReLU::new(3, 0.25), 4, 3, &mut rng::seeded(11));
+    }
+
+    #[test]
+    fn loss_gradients_match_finite_differences()
+    {
+        use nets::*;
+
+        let mut r = rng::seeded(5);
+        let z = matrix::Matrix::new_rand((4, 3), -2.0, 2.0, &mut r);
+        let onehot = matrix::Matrix::with_vec((4, 3),
+                                              vec![1.0, 0.0, 0.0,
+                                                   0.0, 1.0, 0.0,
+                                                   0.0, 0.0, 0.0,
+                                                   0.0, 0.0, 1.0]);
+        let losses: Vec<Box<dyn Loss>> = vec![Box::new(CrossEntropy::new()),
+                                              Box::new(MSE),
+                                              Box::new(MAE),
+                                              Box::new(Huber { delta: 0.5 }),
+                                              Box::new(Hinge),
+                                              Box::new(BinaryCrossEntropy)];
+        let h = 1e-2;
+        for loss in &losses {
+            let (_, grad) = loss.loss(&z, &onehot);
+            for k in 0..z.len() {
+                let (mut up, mut down) = (z.clone(), z.clone());
+                up.a[k] += h;
+                down.a[k] -= h;
+                let numeric = (loss.loss(&up, &onehot).0
+                               - loss.loss(&down, &onehot).0)
+                              / (2.0 * h);
+                assert!((numeric - grad.a[k]).abs() < 1e-2,
+                        "{} at {}: numeric {} analytic {}",
+                        loss.name(),
+                        k,
+                        numeric,
+                        grad.a[k]);
+            }
+        }
+
+        let y = matrix::Matrix::with_vec((2, 1), vec![1.0, 0.0]);
+        let perfect = matrix::Matrix::with_vec((2, 1), vec![1.0, 0.0]);
+        assert_eq!(MSE.loss(&perfect, &y).0, 0.0);
+        assert_eq!(Hinge.loss(&perfect, &y).0, 0.0);
+        let far = matrix::Matrix::with_vec((2, 1), vec![3.0, 0.0]);
+        assert_eq!(Huber { delta: 1.0 }.loss(&far, &y).0, 0.75);
+        let big = matrix::Matrix::with_vec((2, 1), vec![-200.0, 200.0]);
+        assert!(BinaryCrossEntropy.loss(&big, &y).0.is_finite());
+    }
+
+    #[test]
+    fn softmax_survives_huge_logits()
+    {
+        use nets::*;
+
+        let z = matrix::Matrix::with_vec((3, 2), vec![1000.0, -1000.0,
+                                                      999.0, 0.0,
+                                                      -5.0, 1000.0]);
+        let y = matrix::Matrix::with_vec((3, 2), vec![0.0, 1.0,
+                                                      1.0, 0.0,
+                                                      0.0, 0.0]);
+        let sm = Softmax::new();
+        let p = sm.f(z.clone());
+        assert!(p.a.iter().all(|e| e.is_finite()));
+        for j in 0..2 {
+            assert!((p.col(j).a.iter().sum::<f32>() - 1.0).abs() < 1e-5);
+        }
+        assert!((p.a[0] - 1.0 / (1.0 + (-1.0f32).exp())).abs() < 1e-6);
+
+        // log_softmax stays exact where exp(log p) would underflow
+        let log_p = sm.log_softmax(&z);
+        assert!((log_p.a[1] + 2000.0).abs() < 1e-2);
+
+        let (loss, grad) = sm.cross_entropy(&z, &y);
+        assert!((loss - (1.0 + (-1.0f32).exp().ln_1p() + 2000.0)).abs() < 1e-2);
+        assert!(grad.a.iter().all(|e| e.is_finite()));
+
+        // a hotter softmax is flatter but keeps the same order
+        let hot = Softmax::with_temperature(1000.0).f(z.clone());
+        assert!(hot.a[0] < p.a[0] && hot.a[0] > hot.a[2]);
+        let ce = CrossEntropy::with_temperature(2.0);
+        let (_, g) = ce.loss(&z, &y);
+        let (_, g1) = CrossEntropy::new().loss(&z.map(|e| e / 2.0), &y);
+        assert_close(&g, &g1.map(|e| e / 2.0));
+    }
+
+    #[test]
+    fn optimizers_minimize_a_quadratic()
+    {
+        use nets::*;
+
+        let target = matrix::Matrix::with_vec((3, 1), vec![1.0, -2.0, 0.5]);
+        let opts: Vec<(Box<dyn Optimizer>, f32)> =
+            vec![(Box::new(Sgd), 0.1),
+                 (Box::new(Momentum::new(0.9)), 0.05),
+                 (Box::new(Nesterov::new(0.9)), 0.05),
+                 (Box::new(AdaGrad::new()), 0.5),
+                 (Box::new(RMSProp::new(0.9)), 0.01),
+                 (Box::new(Adam::new()), 0.05),
+                 (Box::new(AdamW::new()), 0.05)];
+
+        for (mut opt, lr) in opts {
+            // two slots, so state can't leak from one parameter to another
+            let mut p = vec![matrix::Matrix::new_const((3, 1), 0.0),
+                             matrix::Matrix::new_const((3, 1), 3.0)];
+            for _ in 0..1000 {
+                opt.begin_step();
+                for (slot, p) in p.iter_mut().enumerate() {
+                    let g = &*p - &target;
+                    opt.update(slot, p, &g, lr);
+                }
+            }
+            for p in &p {
+                let dist = (p - &target).a
+                                        .iter()
+                                        .map(|e| e * e)
+                                        .sum::<f32>()
+                                        .sqrt();
+                assert!(dist < 0.05,
+                        "{} ended at {:?}",
+                        opt.name(),
+                        p.a);
+            }
+        }
+
+        // adam's first step is lr in the direction of the gradient's sign
+        let mut adam = Adam::new();
+        let mut p = matrix::Matrix::new_const((2, 1), 0.0);
+        adam.begin_step();
+        adam.update(0, &mut p, &matrix::Matrix::with_vec((2, 1),
+                                                         vec![5.0, -0.01]),
+                    0.1);
+        assert!((p.a[0] + 0.1).abs() < 1e-4 && (p.a[1] - 0.1).abs() < 1e-4);
+
+        // adamw leaves the decay to the layers, so no gradient, no change
+        let mut adamw = AdamW::new();
+        let mut p = matrix::Matrix::new_const((1, 1), 2.0);
+        adamw.begin_step();
+        adamw.update(0, &mut p, &matrix::Matrix::new_const((1, 1), 0.0), 0.1);
+        assert_eq!(p.a[0], 2.0);
+    }
+
+    #[test]
+    fn schedules_follow_their_curves()
+    {
+        use nets::*;
+
+        let close = |a: f32, b: f32| (a - b).abs() < 1e-5;
+
+        let step = StepDecay::new(10, 0.5);
+        assert!(close(step.rate(9, 1.0), 1.0));
+        assert!(close(step.rate(25, 1.0), 0.25));
+
+        let exp = Exponential::new(10, 0.5);
+        assert!(close(exp.rate(5, 1.0), 0.5f32.sqrt()));
+
+        let cos = CosineRestarts::new(10, 2, 0.0);
+        assert!(close(cos.rate(0, 1.0), 1.0) && close(cos.rate(5, 1.0), 0.5));
+        // the second period is 20 long and starts over at the top
+        assert!(close(cos.rate(10, 1.0), 1.0) && close(cos.rate(20, 1.0), 0.5));
+        // a zero period set by hand acts as 1 instead of hanging
+        let zero = CosineRestarts { period: 0, mult: 1, min: 0.0 };
+        assert!(close(zero.rate(7, 1.0), 1.0));
+        assert!(StepDecay { every: 0, factor: 0.5 }.rate(2, 1.0).is_finite());
+
+        let decay = StepDecay::new(10, 0.5);
+        let warm = Warmup::new(4, Box::new(decay));
+        assert!(close(warm.rate(0, 1.0), 0.25));
+        assert!(close(warm.rate(3, 1.0), 1.0));
+        assert!(close(warm.rate(14, 1.0), 0.5));
+
+        let one = OneCycle::new(100);
+        assert!(close(one.rate(0, 1.0), 0.04) && close(one.rate(30, 1.0), 1.0));
+        assert!(one.rate(65, 1.0) < 1.0);
+        assert!(one.rate(65, 1.0) > one.rate(90, 1.0));
+        assert!(close(one.rate(100, 1.0), 0.04 / 1e4));
+
+        let mut plateau = ReduceOnPlateau::new(0.1, 1);
+        for &loss in &[1.0, 0.5, 0.5, 0.6] {
+            plateau.observe(loss);
+        }
+        assert!(close(plateau.rate(0, 1.0), 0.1));
+        plateau.observe(0.4);
+        assert!(close(plateau.rate(0, 1.0), 0.1));
+    }
+
+    #[test]
+    fn regularization_is_per_layer()
+    {
+        use nets::*;
+
+        let mut r = rng::seeded(8);
+        let be = Reference;
+        let x = matrix::Matrix::new_rand((3, 4), -1.0, 1.0, &mut r);
+        let g = matrix::Matrix::new_rand((2, 4), -1.0, 1.0, &mut r);
+        let mut layer = Layer::new_rand(Act::Tanh, 3, 2, &mut r);
+
+        let grads = |layer: &mut Layer<Act>, reg: Regularization| {
+            layer.set_regularization(reg);
+            layer.zero_grad();
+            layer.backprop(g.clone(), x.clone(), &be);
+            layer.gradients()
+        };
+
+        let plain = grads(&mut layer, Regularization::none());
+        let reg = Regularization { l2: 0.5,
+                                   l1: 0.25,
+                                   decay_bias: true,
+                                   ..Regularization::none() };
+        let both = grads(&mut layer, reg);
+
+        // 4 samples, each adding l2 * w + l1 * sign(w)
+        let penalty = |e: f32| 4.0 * (0.5 * e + 0.25 * e.signum());
+        assert_close(&both[0], &(&plain[0] + &layer.w.map(penalty)));
+        assert_close(&both[1], &(&plain[1] + &layer.b().map(penalty)));
+
+        let no_bias = grads(&mut layer, Regularization::l2(0.5));
+        assert_close(&no_bias[1], &plain[1]);
+
+        layer.set_regularization(Regularization { weight_decay: 0.5,
+                                                  max_norm: Some(0.1),
+                                                  ..Regularization::none() });
+        let b = layer.b().clone();
+        layer.constrain(0.1);
+        for row in layer.w.rows() {
+            let norm = row.iter().map(|e| e * e).sum::<f32>().sqrt();
+            assert!(norm <= 0.1 + 1e-6);
+        }
+        assert_close(layer.b(), &b);
+    }
+
+    #[test]
+    fn dropout_only_while_training()
+    {
+        use nets::*;
+
+        let mut r = rng::seeded(4);
+        let be = Reference;
+        let mut layer = Layer::new_rand(Act::Sigmoid, 50, 3, &mut r);
+        layer.set_dropout(0.4);
+        layer.set_regularization(Regularization::none());
+        let x = matrix::Matrix::new_rand((50, 8), -1.0, 1.0, &mut r);
+        let g = matrix::Matrix::new_rand((3, 8), -1.0, 1.0, &mut r);
+
+        // a training forward pass with a fixed stream, giving the output
+        // and what it saved for backward
+        let run = |layer: &Layer<Act>, train: bool| {
+            let mut rng = rng::stream(1, &[2, 3]);
+            let mut pass = Pass { train, be: &be, rng: &mut rng };
+            let mut cache = Cache::new();
+            let out = layer.forward(x.clone(), &mut cache, &mut pass);
+            (out, cache)
+        };
+
+        assert_close(&run(&layer, false).0, &layer.prop(x.clone(), &be));
+        let (out, mut cache) = run(&layer, true);
+        assert_close(&out, &run(&layer, true).0);
+        let moved = &out - &layer.prop(x.clone(), &be);
+        assert!(moved.a.iter().map(|e| e * e).sum::<f32>().sqrt() > 0.1);
+
+        let mask = cache.pop();
+        let dropped = mask.a.iter().filter(|e| **e == 0.0).count();
+        assert!(dropped > 100 && dropped < 220, "dropped {}", dropped);
+        let kept = 1.0 / 0.6;
+        assert!(mask.a.iter().all(|e| *e == 0.0 || (e - kept).abs() < 1e-6));
+        cache.push(mask);
+
+        // the gradient through the same mask matches finite differences
+        let mut rng = rng::stream(1, &[2, 3]);
+        let mut pass = Pass { train: true, be: &be, rng: &mut rng };
+        let dx = layer.backward(g.clone(), &mut cache, &mut pass);
+        let loss = |layer: &Layer<Act>, x: &matrix::Matrix<f32>| -> f32 {
+            let mut rng = rng::stream(1, &[2, 3]);
+            let mut pass = Pass { train: true, be: &be, rng: &mut rng };
+            let out = layer.forward(x.clone(), &mut Cache::new(), &mut pass);
+            out.h_prod(&g).a.iter().sum()
+        };
+        let h = 1e-2;
+        for k in &[0, 17, 123, 399] {
+            let (mut up, mut down) = (x.clone(), x.clone());
+            up.a[*k] += h;
+            down.a[*k] -= h;
+            let numeric = (loss(&layer, &up) - loss(&layer, &down)) / (2.0 * h);
+            assert!((numeric - dx.a[*k]).abs() < 1e-2,
+                    "{}: numeric {} analytic {}",
+                    k,
+                    numeric,
+                    dx.a[*k]);
+        }
+        assert!(!layer.sparse_ok());
+    }
+
+    // d sum(g .* forward(x)) / dx by central differences, for modules
+    // whose forward doesn't depend on the rng.
+    fn numeric_input_grad<M>(m: &M,
+                             x: &matrix::Matrix<f32>,
+                             g: &matrix::Matrix<f32>)
+                             -> matrix::Matrix<f32>
+        where M: ::nets::Module
+    {
+        let be = ::nets::Reference;
+        let mut rng = rng::seeded(0);
+        let mut loss = |x: matrix::Matrix<f32>| -> f32 {
+            let mut pass = ::nets::Pass { train: true, be: &be, rng: &mut rng };
+            let out = m.forward(x, &mut ::nets::Cache::new(), &mut pass);
+            out.h_prod(g).a.iter().sum()
+        };
+
+        let h = 1e-2;
+        let mut d = x.clone();
+        for k in 0..x.len() {
+            let (mut up, mut down) = (x.clone(), x.clone());
+            up.a[k] += h;
+            down.a[k] -= h;
+            d.a[k] = (loss(up) - loss(down)) / (2.0 * h);
+        }
+        d
+    }
+
+    #[test]
+    fn batchnorm_normalizes_and_backprops()
+    {
+        use nets::*;
+
+        let mut r = rng::seeded(6);
+        let be = Reference;
+        let mut bn = BatchNorm::new(3);
+        let x = matrix::Matrix::new_rand((3, 6), -2.0, 5.0, &mut r);
+        let g = matrix::Matrix::new_rand((3, 6), -1.0, 1.0, &mut r);
+
+        // shift gamma and beta off 1 and 0 so they matter
+        for (p, _) in bn.params_and_grads() {
+            p.map_inplace(|e| e + 0.3);
+        }
+
+        let mut rng = rng::seeded(0);
+        let mut pass = Pass { train: true, be: &be, rng: &mut rng };
+        let mut cache = Cache::new();
+        let y = bn.forward(x.clone(), &mut cache, &mut pass);
+        for row in y.rows() {
+            let mean = row.iter().sum::<f32>() / 6.0;
+            assert!((mean - 0.3).abs() < 1e-4);
+        }
+
+        let dx = bn.backward(g.clone(), &mut cache, &mut pass);
+        assert_close(&dx, &numeric_input_grad(&bn, &x, &g));
+
+        // gamma's gradient is sum(g .* x_hat), beta's sum(g)
+        assert_close(&bn.gradients()[1], &g.sum_cols());
+
+        // zero_grad drops the batch statistics along with the gradients
+        bn.zero_grad();
+        bn.constrain(0.1);
+        assert!(bn.running_mean().a.iter().all(|e| *e == 0.0));
+
+        // the running stats only move once the update is done
+        let mut fresh = BatchNorm::new(3);
+        fresh.forward(x.clone(), &mut Cache::new(), &mut pass);
+        assert!(fresh.running_mean().a.iter().all(|e| *e == 0.0));
+        fresh.constrain(0.1);
+        let batch_mean = x.sum_cols().map(|e| e / 6.0);
+        let mean = batch_mean.map(|e| 0.1 * e);
+        assert_close(fresh.running_mean(), &mean);
+        // the variance goes in unbiased, over n - 1
+        let var = x.rows()
+                   .zip(&batch_mean.a)
+                   .map(|(row, m)| {
+                       let v = row.iter().map(|e| (e - m) * (e - m));
+                       0.9 + 0.1 * v.sum::<f32>() / 5.0
+                   })
+                   .collect();
+        let var = matrix::Matrix::with_vec((3, 1), var);
+        assert_close(fresh.running_var(), &var);
+
+        // evaluating uses them, one sample at a time
+        let mut pass = Pass { train: false, be: &be, rng: &mut rng };
+        let one = fresh.forward(x.col(0), &mut Cache::new(), &mut pass);
+        let all = fresh.forward(x.clone(), &mut Cache::new(), &mut pass);
+        assert_close(&one, &all.col(0));
+
+        // and so does a lone sample in training, instead of panicking,
+        // without touching the running stats
+        let mut pass = Pass { train: true, be: &be, rng: &mut rng };
+        let mut cache = Cache::new();
+        let lone = fresh.forward(x.col(0), &mut cache, &mut pass);
+        assert_close(&lone, &one);
+        let dx = fresh.backward(g.col(0), &mut cache, &mut pass);
+        assert_close(&dx, &numeric_input_grad(&fresh, &x.col(0), &g.col(0)));
+        fresh.constrain(0.1);
+        assert_close(fresh.running_mean(), &mean);
+
+        let mut copy = BatchNorm::new(3);
+        copy.load_buffers(fresh.buffers());
+        assert_close(copy.running_var(), fresh.running_var());
+    }
+
+    #[test]
+    #[should_panic(expected = "a net with batchnorm needs set_batched(true)")]
+    fn batchnorm_refuses_unbatched_training()
+    {
+        use nets::*;
+
+        let layers: Vec<Box<dyn Module>> = vec![Box::new(BatchNorm::new(3))];
+        let mut net = FFNet::new(Sequential::new(layers),
+                                 Box::new(Sgd),
+                                 Vec::new(),
+                                 1);
+        let xs = vec![matrix::Matrix::new_const((3, 1), 1.0)];
+        net.train(4, 0.1, (xs.clone(), xs));
+    }
+
+    #[test]
+    fn batched_threads_never_get_a_lone_sample()
+    {
+        use nets::*;
+
+        let mut r = rng::seeded(44);
+        let xs: Vec<_> = (0..3)
+            .map(|_| matrix::Matrix::new_rand((3, 1), 1.0, 2.0, &mut r))
+            .collect();
+        let layers: Vec<Box<dyn Module>> = vec![Box::new(BatchNorm::new(3))];
+        // 3 samples on 2 threads used to be 3 chunks of one, which never
+        // touched the running statistics
+        let mut net = FFNet::new(Sequential::new(layers),
+                                 Box::new(Sgd),
+                                 Vec::new(),
+                                 2);
+        net.set_loss(Box::new(MSE));
+        net.set_batched(true);
+        net.update_with_batch(xs.iter().zip(&xs).collect());
+        net.update_params(3, 0.1);
+        let any = net.layers.modules()[0].as_any();
+        let bn = any.downcast_ref::<BatchNorm>().unwrap();
+        assert!(bn.running_mean().a.iter().all(|e| *e > 0.1));
+    }
+
+    #[test]
+    fn layernorm_works_per_sample()
+    {
+        use nets::*;
+
+        let mut r = rng::seeded(12);
+        let be = Reference;
+        let mut ln = LayerNorm::new(5);
+        for (p, _) in ln.params_and_grads() {
+            let shift = matrix::Matrix::new_rand(p.dim, -0.5, 0.5, &mut r);
+            p.add_by(&shift);
+        }
+        let x = matrix::Matrix::new_rand((5, 3), -3.0, 3.0, &mut r);
+        let g = matrix::Matrix::new_rand((5, 3), -1.0, 1.0, &mut r);
+
+        let mut rng = rng::seeded(0);
+        let mut pass = Pass { train: true, be: &be, rng: &mut rng };
+        let mut cache = Cache::new();
+        let y = ln.forward(x.clone(), &mut cache, &mut pass);
+        let dx = ln.backward(g.clone(), &mut cache, &mut pass);
+        assert_close(&dx, &numeric_input_grad(&ln, &x, &g));
+
+        // a single column comes out the same as it does in the batch
+        let mut cache = Cache::new();
+        let y1 = ln.forward(x.col(1), &mut cache, &mut pass);
+        assert_close(&y1, &y.col(1));
+        let dx1 = ln.backward(g.col(1), &mut cache, &mut pass);
+        assert_close(&dx1, &dx.col(1));
+
+        // and the parameter gradients add up over both passes
+        let grads = ln.gradients();
+        assert_close(&grads[1], &(&g.sum_cols() + &g.col(1)));
+    }
+
+    #[test]
+    fn conv_and_pooling_backprop()
+    {
+        use nets::*;
+
+        let mut r = rng::seeded(21);
+        let be = Reference;
+        let mut rng = rng::seeded(0);
+
+        // 2 channels of 5x5, 3 kernels of 3x3 with stride 2 and padding 1
+        let conv = Conv2d::new_rand(Act::Tanh, (2, 5, 5), 3, 3, 2, 1, &mut r);
+        assert_eq!(conv.out_shape(), (3, 3, 3));
+        let x = matrix::Matrix::new_rand((50, 2), -1.0, 1.0, &mut r);
+
+        // output channel o at (i, j) of sample b, computed directly
+        let naive = |o: usize, i: usize, j: usize, b: usize| -> f32 {
+            let mut sum = conv.b().a[o];
+            for c in 0..2 {
+                for ki in 0..3 {
+                    for kj in 0..3 {
+                        let (y, z) = (2 * i + ki, 2 * j + kj);
+                        if y < 1 || z < 1 || y > 5 || z > 5 {
+                            continue;
+                        }
+                        let px: f32 = x.a[((c * 5 + y - 1) * 5 + z - 1)
+                                          * 2 + b];
+                        sum += conv.w.a[o * 18 + (c * 3 + ki) * 3 + kj]
+                               * px.tanh();
+                    }
+                }
+            }
+            sum
+        };
+        let mut pass = Pass { train: true, be: &be, rng: &mut rng };
+        let mut cache = Cache::new();
+        let y = conv.forward(x.clone(), &mut cache, &mut pass);
+        assert_close(&y, &matrix::Matrix::from_fn((27, 2), |r, b| {
+            naive(r / 9, r % 9 / 3, r % 3, b)
+        }));
+
+        let g = matrix::Matrix::new_rand((27, 2), -1.0, 1.0, &mut r);
+        let dx = conv.backward(g.clone(), &mut cache, &mut pass);
+        assert_close(&dx, &numeric_input_grad(&conv, &x, &g));
+
+        // a prelu's slopes get trained too, one per input row here, with x
+        // kept away from its kink
+        let mut prelu = Conv2d::new_rand(PReLU::new(50, 0.25),
+                                         (2, 5, 5),
+                                         3,
+                                         3,
+                                         2,
+                                         1,
+                                         &mut r);
+        assert_eq!(prelu.parameters().len(), 3);
+        let x = x.map(|e| if e < 0.0 { e - 0.2 } else { e + 0.2 });
+        let check = GradCheck::new();
+        let checks = check.check_module(&mut prelu, &MSE, &x, &g);
+        assert_eq!(checks.len(), 3 * 18 + 3 + 50 + 50 * 2);
+        assert!(check.failures(&checks).is_empty(),
+                "{:?}",
+                check.failures(&checks).first());
+        prelu.zero_grad();
+        assert!(prelu.gradients()[2].a.iter().all(|e| *e == 0.0));
+
+        // distinct and well apart, so nudging x never changes which is max
+        let x = matrix::Matrix::from_fn((2 * 4 * 6, 3), |i, j| {
+            (((i * 3 + j) * 37 % 144) as f32 - 72.0) * 0.025
+        });
+        let g = matrix::Matrix::new_rand((2 * 2 * 3, 3), -1.0, 1.0, &mut r);
+        let max = MaxPool2d::new((2, 4, 6), 2, 2);
+        let avg = AvgPool2d::new((2, 4, 6), 2, 2);
+        for m in &[&max as &dyn Module, &avg] {
+            let mut cache = Cache::new();
+            m.forward(x.clone(), &mut cache, &mut pass);
+            let dx = m.backward(g.clone(), &mut cache, &mut pass);
+            let numeric = if m.name().starts_with("max") {
+                numeric_input_grad(&max, &x, &g)
+            } else {
+                numeric_input_grad(&avg, &x, &g)
+            };
+            assert_close(&dx, &numeric);
+        }
+
+        // and it all stacks up with dense layers
+        let conv = Conv2d::new_rand(Act::Relu, (1, 6, 6), 4, 3, 1, 0, &mut r);
+        let pool = MaxPool2d::new(conv.out_shape(), 2, 2);
+        let flat = Flatten::new(pool.out_shape());
+        let dense = Layer::new_rand(Act::Relu, 16, 10, &mut r);
+        let net = Sequential::new(vec![Box::new(conv),
+                                       Box::new(pool),
+                                       Box::new(flat),
+                                       Box::new(dense)]);
+        let x = matrix::Matrix::new_rand((36, 5), 0.0, 1.0, &mut r);
+        let (out, caches) = net.forward(x, &mut pass);
+        assert_eq!(out.dim, (10, 5));
+        let dx = net.backward(out, caches, &mut pass);
+        assert_eq!(dx.dim, (36, 5));
+    }
+
+    #[test]
+    fn initializers_have_the_right_spread()
+    {
+        use nets::*;
+
+        let mut r = rng::seeded(3);
+        let std = |m: &matrix::Matrix<f32>| {
+            let mean = m.a.iter().sum::<f32>() / m.len() as f32;
+            (m.a.iter().map(|e| (e - mean).powi(2)).sum::<f32>()
+             / m.len() as f32).sqrt()
+        };
+        let dim = (200, 300);
+
+        let he = Init::HeNormal.matrix(dim, &mut r);
+        assert!((std(&he) - (2.0f32 / 300.0).sqrt()).abs() < 0.003);
+        let lecun = Init::LeCunNormal.matrix(dim, &mut r);
+        assert!((std(&lecun) - (1.0f32 / 300.0).sqrt()).abs() < 0.003);
+        let xavier = Init::XavierNormal.matrix(dim, &mut r);
+        assert!((std(&xavier) - (2.0f32 / 500.0).sqrt()).abs() < 0.003);
+
+        let limit = (6.0f32 / 500.0).sqrt();
+        let xu = Init::XavierUniform.matrix(dim, &mut r);
+        assert!(xu.a.iter().all(|e| e.abs() <= limit));
+        assert!(xu.a.iter().any(|e| e.abs() > 0.9 * limit));
+        let hu = Init::HeUniform.matrix(dim, &mut r);
+        assert!(hu.a.iter().all(|e| e.abs() <= (6.0f32 / 300.0).sqrt()));
+
+        let tn = Init::TruncatedNormal(0.5).matrix(dim, &mut r);
+        assert!(tn.a.iter().all(|e| e.abs() <= 1.0));
+        assert!(std(&tn) > 0.4 && std(&tn) < 0.5);
+
+        let c = Init::Constant(0.25).matrix((3, 2), &mut r);
+        assert!(c.a.iter().all(|e| *e == 0.25));
+
+        // orthonormal rows when wide, orthonormal columns when tall
+        let wide = Init::Orthogonal(1.0).matrix((4, 7), &mut r);
+        let id4 = matrix::Matrix::from_fn((4, 4), |i, j| (i == j) as u8 as f32);
+        assert_close(&wide.mul_tr(&wide), &id4);
+        let tall = Init::Orthogonal(2.0).matrix((7, 4), &mut r);
+        assert_close(&tall.mul_tl(&tall), &(&id4 * 4.0));
+
+        let layer = Layer::new_init(Act::Relu,
+                                    5,
+                                    3,
+                                    Init::HeNormal,
+                                    Init::Constant(0.0),
+                                    &mut r);
+        assert_eq!(layer.w.dim, (3, 5));
+        assert!(layer.b().a.iter().all(|e| *e == 0.0));
+
+        // a conv kernel's fans count the k * k positions it covers, 4 * 25
+        // in and 50 * 25 out, not the 100 and 50 of its shape
+        let mut conv = Conv2d::new_rand(Act::Tanh, (4, 9, 9), 50, 5, 1, 0,
+                                        &mut r);
+        conv.initialize(Init::XavierNormal, Init::Constant(0.0), &mut r);
+        assert_eq!(conv.w.dim, (50, 100));
+        assert!((std(&conv.w) - (2.0f32 / 1350.0).sqrt()).abs() < 0.003);
+    }
+
+    #[test]
+    #[should_panic(expected = "HeNormal is for weights, not biases")]
+    fn scaled_inits_are_only_for_weights()
+    {
+        use nets::*;
+
+        Layer::new_init(Act::Relu,
+                        5,
+                        3,
+                        Init::HeNormal,
+                        Init::HeNormal,
+                        &mut rng::seeded(3));
+    }
+
+    #[test]
+    fn gradcheck_covers_every_activation_and_loss()
+    {
+        use nets::*;
+
+        let mut r = rng::seeded(48);
+        let check = GradCheck::new();
+        // away from 0, where relu and friends have their kink
+        let x = matrix::Matrix::from_fn((4, 5), |i, j| {
+            let e = ((i * 5 + j) * 7 % 20) as Number / 10.0 - 0.95;
+            if e < 0.0 { e - 0.2 } else { e + 0.2 }
+        });
+        let y = matrix::Matrix::from_fn((3, 5), |i, j| {
+            if i == j % 3 { 1.0 } else { 0.0 }
+        });
+
+        let assert_ok = |what: &str, checks: &[Check]| {
+            let bad = check.failures(checks);
+            assert!(bad.is_empty(), "{}: {:?}", what, bad.first());
+        };
+
+        for act in Act::all() {
+            let mut layer = Layer::new_rand(act, 4, 3, &mut r);
+            layer.set_regularization(Regularization::none());
+            let checks = check.check_module(&mut layer, &MSE, &x, &y);
+            assert_eq!(checks.len(), 3 * 4 + 3 + 4 * 5);
+            assert_ok(&act.to_string(), &checks);
+        }
+        let mut prelu = Layer::new_rand(PReLU::new(4, 0.25), 4, 3, &mut r);
+        prelu.set_regularization(Regularization::none());
+        assert_ok("prelu", &check.check_module(&mut prelu, &MSE, &x, &y));
+
+        let losses: Vec<Box<dyn Loss>> =
+            vec![Box::new(CrossEntropy::new()),
+                 Box::new(CrossEntropy::with_temperature(2.0)),
+                 Box::new(MSE),
+                 Box::new(MAE),
+                 Box::new(Huber { delta: 0.5 }),
+                 Box::new(Hinge),
+                 Box::new(BinaryCrossEntropy)];
+        for loss in losses {
+            let mut layers: Vec<Box<dyn Module>> = Vec::new();
+            for &(into, out, act) in &[(4, 6, Act::Tanh), (6, 3, Act::Gelu)] {
+                let mut l = Layer::new_init(act,
+                                            into,
+                                            out,
+                                            Init::XavierNormal,
+                                            Init::Constant(0.1),
+                                            &mut r);
+                l.set_regularization(Regularization::none());
+                layers.push(Box::new(l));
+            }
+            let mut net = FFNet::new(Sequential::new(layers),
+                                     Box::new(Sgd),
+                                     Vec::new(),
+                                     1);
+            let name = loss.name();
+            net.set_loss(loss);
+            // mae has a kink wherever an output hits its target, so keep
+            // the targets out of reach
+            let y = if name == "mae" {
+                y.map(|e| 3.0 * e - 1.0)
+            } else {
+                y.clone()
+            };
+            assert_ok(name, &net.check_gradients(&check, &x, &y));
+        }
+
+        // and it does catch a wrong gradient, even one only a little off
+        let mut layer = Layer::new_rand(Act::Tanh, 4, 3, &mut r);
+        layer.set_regularization(Regularization::l2(1e-3));
+        let checks = check.check_module(&mut layer, &MSE, &x, &y);
+        assert!(!check.failures(&checks).is_empty());
+
+        // the forward passes leave no batch statistics behind
+        let mut bn = BatchNorm::new(4);
+        let y4 = matrix::Matrix::new_rand((4, 5), -1.0, 1.0, &mut r);
+        assert_ok("batchnorm", &check.check_module(&mut bn, &MSE, &x, &y4));
+        bn.constrain(0.1);
+        assert!(bn.running_mean().a.iter().all(|e| *e == 0.0));
+    }
+
+    #[test]
+    fn clipping_caps_value_and_norm()
+    {
+        use nets::*;
+
+        let mut r = rng::seeded(49);
+        let xs: Vec<_> = (0..4).map(|_| {
+                                   matrix::Matrix::new_rand((4, 1),
+                                                            -3.0,
+                                                            3.0,
+                                                            &mut r)
+                               })
+                               .collect();
+        let y = matrix::Matrix::with_vec((3, 1), vec![0.0, 1.0, 0.0]);
+        let layers: Vec<Box<dyn Module>> =
+            vec![Box::new(Layer::new_rand(Act::Tanh, 4, 5, &mut r)),
+                 Box::new(Layer::new_rand(Act::Relu, 5, 3, &mut r))];
+        let mut net = FFNet::new(Sequential::new(layers),
+                                 Box::new(Sgd),
+                                 Vec::new(),
+                                 1);
+        let batch = |net: &mut FFNet| {
+            net.zero_grad();
+            net.update_with_batch(xs.iter().map(|x| (x, &y)).collect());
+        };
+        let grads = |net: &FFNet| -> Vec<Number> {
+            net.layers
+               .modules()
+               .iter()
+               .flat_map(|m| m.gradients())
+               .flat_map(|g| g.a)
+               .map(|e| e / 4.0)
+               .collect()
+        };
+        let l2 = |g: &[Number]| g.iter().map(|e| e * e).sum::<Number>().sqrt();
+
+        // nothing set, nothing changes
+        batch(&mut net);
+        let before = grads(&net);
+        let norm = net.clip_gradients(4);
+        assert!((norm - l2(&before)).abs() < 1e-4);
+        assert_eq!(grads(&net), before);
+
+        let max = before.iter().fold(0.0 as Number, |m, e| m.max(e.abs()));
+        net.set_clipping(Clipping::value(max / 2.0));
+        batch(&mut net);
+        assert_eq!(net.clip_gradients(4), norm);
+        for (a, b) in grads(&net).iter().zip(&before) {
+            assert!((a - b.max(-max / 2.0).min(max / 2.0)).abs() < 1e-5);
+        }
+
+        net.set_clipping(Clipping::norm(norm / 3.0));
+        batch(&mut net);
+        assert_eq!(net.clip_gradients(4), norm);
+        let after = grads(&net);
+        assert!((l2(&after) - norm / 3.0).abs() < 1e-4);
+        for (a, b) in after.iter().zip(&before) {
+            assert!((a * 3.0 - b).abs() < 1e-4);
+        }
+
+        // under the cap, untouched
+        net.set_clipping(Clipping::norm(norm * 2.0));
+        batch(&mut net);
+        net.clip_gradients(4);
+        assert_eq!(grads(&net), before);
+    }
+
+    #[test]
+    #[should_panic(expected = "the norm cap has to be positive, not 0")]
+    fn clipping_caps_are_positive()
+    {
+        ::nets::Clipping::norm(0.0);
+    }
+
+    #[test]
+    fn training_stops_at_max_batches()
+    {
+        use nets::*;
+
+        let mut r = rng::seeded(46);
+        let layer = Layer::new_rand(Act::Tanh, 3, 2, &mut r);
+        let mut net = FFNet::new(Sequential::new(vec![Box::new(layer)]),
+                                 Box::new(Sgd),
+                                 Vec::new(),
+                                 1);
+        let xs = vec![matrix::Matrix::new_rand((3, 1), -1.0, 1.0, &mut r)];
+        let ys = vec![matrix::Matrix::with_vec((2, 1), vec![1.0, 0.0])];
+        let before = net.layers.modules()[0].parameters()[0].clone();
+
+        net.set_max_batches(5);
+        net.train(2, 0.1, (xs, ys));
+        let after = net.layers.modules()[0].parameters()[0];
+        assert!(after.a != before.a);
+    }
+
+    #[test]
+    fn read_idx_keeps_the_last_image()
+    {
+        use idx::read_idx;
+        use std::fs;
+
+        // three 2x2 images, then three labels
+        let mut images = vec![0, 0, 8, 3, 0, 0, 0, 3, 0, 0, 0, 2, 0, 0, 0, 2];
+        images.extend(0..12);
+        let labels = vec![0, 0, 8, 1, 0, 0, 0, 3, 7, 0, 9];
+
+        let dir = ::std::env::temp_dir();
+        let name = |kind| {
+            let file = format!("mnist-idx-{}-{}", kind, ::std::process::id());
+            dir.join(file).to_str().unwrap().to_string()
+        };
+        let (xs_path, ys_path) = (name("images"), name("labels"));
+        fs::write(&xs_path, images).unwrap();
+        fs::write(&ys_path, labels).unwrap();
+
+        let xs = read_idx(&xs_path, 10);
+        assert_eq!(xs.len(), 3);
+        assert_eq!(xs[2].a, vec![8.0, 9.0, 10.0, 11.0]);
+        assert_eq!(read_idx(&xs_path, 2).len(), 2);
+        let ys = read_idx(&ys_path, 10);
+        assert_eq!(ys.len(), 3);
+        assert_eq!(ys[2].a[9], 1.0);
+
+        fs::remove_file(xs_path).unwrap();
+        fs::remove_file(ys_path).unwrap();
+    }
+
+    #[test]
+    fn saved_nets_load_back_and_reject_bad_files()
+    {
+        use nets::*;
+        use std::fs;
+
+        assert_eq!(::nets::save::crc32(b"123456789"), 0xcbf4_3926);
+
+        let mut r = rng::seeded(50);
+        let mut dense = Layer::new_rand(Act::LeakyRelu(0.2), 18, 8, &mut r);
+        dense.set_dropout(0.25);
+        dense.set_regularization(Regularization { l1: 0.5,
+                                                  max_norm: Some(3.0),
+                                                  ..Regularization::l2(0.1) });
+        let mut bn = BatchNorm::new(8);
+        let mean = matrix::Matrix::new_rand((8, 1), -1.0, 1.0, &mut r);
+        let var = matrix::Matrix::new_rand((8, 1), 0.5, 2.0, &mut r);
+        bn.load_buffers(vec![mean, var]);
+        let layers: Vec<Box<dyn Module>> =
+            vec![Box::new(Conv2d::new_rand(Act::Relu, (1, 6, 6), 2, 3, 1, 1,
+                                           &mut r)),
+                 Box::new(MaxPool2d::new((2, 6, 6), 2, 2)),
+                 Box::new(AvgPool2d::new((2, 3, 3), 1, 1)),
+                 Box::new(Flatten::new((2, 3, 3))),
+                 Box::new(dense),
+                 Box::new(bn),
+                 Box::new(LayerNorm::new(8)),
+                 Box::new(Layer::new_rand(PReLU::new(8, 0.1), 8, 3, &mut r))];
+        let mut net = FFNet::new(Sequential::new(layers),
+                                 Box::new(Sgd),
+                                 Vec::new(),
+                                 1);
+        net.set_loss(Box::new(Huber { delta: 0.7 }));
+        net.set_preprocess(Preprocess { mean: 0.5, std: 2.0 });
+
+        let path = ::std::env::temp_dir()
+            .join(format!("mnist-save-{}.net", ::std::process::id()));
+        let path = path.to_str().unwrap();
+        net.save(path).unwrap();
+
+        let load = |path: &str| FFNet::load(path, Box::new(Sgd), Vec::new(), 1);
+        let loaded = load(path).unwrap();
+        assert_eq!(loaded.loss().name(), "huber");
+        assert_eq!(loaded.preprocess(), net.preprocess());
+        let names = |n: &FFNet| -> Vec<String> {
+            n.layers.modules().iter().map(|m| m.name()).collect()
+        };
+        assert_eq!(names(&loaded), names(&net));
+        let d = loaded.layers.modules()[4]
+                      .as_any()
+                      .downcast_ref::<Layer<Act>>()
+                      .unwrap();
+        assert_eq!(*d.activation(), Act::LeakyRelu(0.2));
+        assert_eq!(d.dropout(), 0.25);
+        assert_eq!(d.regularization().max_norm, Some(3.0));
+        for (a, b) in net.layers.modules().iter().zip(loaded.layers.modules()) {
+            for (x, y) in a.buffers().iter().zip(&b.buffers()) {
+                assert_eq!(x.a, y.a);
+            }
+        }
+        for _ in 0..3 {
+            let x = matrix::Matrix::new_rand((36, 1), 0.0, 3.0, &mut r);
+            assert_eq!(net.eval(&x).a, loaded.eval(&x).a);
+        }
+
+        let good = fs::read(path).unwrap();
+        let broken = |bytes: Vec<u8>| {
+            fs::write(path, bytes).unwrap();
+            load(path).err().unwrap()
+        };
+
+        let mut flipped = good.clone();
+        flipped[40] ^= 1;
+        let e = broken(flipped);
+        assert!(matches!(e, ModelError::Checksum { .. }), "{}", e);
+        let e = broken(good[..good.len() - 9].to_vec());
+        assert!(matches!(e, ModelError::Truncated), "{}", e);
+        let e = broken(b"not a net at all".to_vec());
+        assert!(matches!(e, ModelError::NotAModel), "{}", e);
+        let mut newer = good.clone();
+        newer[8] = 2;
+        let e = broken(newer);
+        assert!(matches!(e, ModelError::Version(2)), "{}", e);
+        assert_eq!(e.to_string(),
+                   "saved with format version 2, this reads version 1");
+        fs::remove_file(path).unwrap();
+        assert!(matches!(load(path).err().unwrap(), ModelError::Io(_)));
+
+        let odd: Vec<Box<dyn Module>> =
+            vec![Box::new(Layer::new_rand(Sigmoid {}, 2, 2, &mut r))];
+        let odd = FFNet::new(Sequential::new(odd),
+                             Box::new(Sgd),
+                             Vec::new(),
+                             1);
+        let e = odd.save(path).err().unwrap();
+        assert!(matches!(e, ModelError::Unsupported(_)), "{}", e);
+
+        // a std or a huber delta that couldn't have been trained with
+        let plain = |r: &mut rng::StdRng| {
+            let l = Layer::new_rand(Act::Relu, 2, 2, r);
+            FFNet::new(Sequential::new(vec![Box::new(l)]),
+                       Box::new(Sgd),
+                       Vec::new(),
+                       1)
+        };
+        for std in [0.0, -1.0, f32::NAN, f32::INFINITY] {
+            let mut bad = plain(&mut r);
+            bad.set_preprocess(Preprocess { mean: 0.0, std });
+            bad.save(path).unwrap();
+            let e = load(path).err().unwrap();
+            assert!(matches!(e, ModelError::Malformed(_)), "{}", e);
+        }
+        for delta in [0.0, -0.5, f32::NAN] {
+            let mut bad = plain(&mut r);
+            bad.set_loss(Box::new(Huber { delta }));
+            bad.save(path).unwrap();
+            let e = load(path).err().unwrap();
+            assert!(matches!(e, ModelError::Malformed(_)), "{}", e);
+        }
+
+        // a conv's prelu slopes come back along with its kernels
+        let mut conv = Conv2d::new_rand(PReLU::new(18, 0.1), (2, 3, 3), 2, 2,
+                                        1, 0, &mut r);
+        let slopes = matrix::Matrix::new_rand((18, 1), 0.0, 0.5, &mut r);
+        *conv.params_and_grads()[2].0 = slopes.clone();
+        let convs = FFNet::new(Sequential::new(vec![Box::new(conv)]),
+                               Box::new(Sgd),
+                               Vec::new(),
+                               1);
+        convs.save(path).unwrap();
+        let back = load(path).unwrap();
+        assert_eq!(back.layers.modules()[0].parameters()[2].a, slopes.a);
+        let x = matrix::Matrix::new_rand((18, 1), -1.0, 1.0, &mut r);
+        assert_eq!(convs.eval(&x).a, back.eval(&x).a);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn preprocessed_inputs_skip_the_sparse_path()
+    {
+        use nets::*;
+
+        let mut r = rng::seeded(51);
+        // mostly zeros, like mnist, until they're standardized
+        let xs: Vec<_> = (0..6)
+            .map(|_| {
+                matrix::Matrix::new_rand((8, 1), -1.0, 1.0, &mut r)
+                    .map(|e: f32| e.max(0.0))
+            })
+            .collect();
+        let ys: Vec<_> = (0..6)
+            .map(|i| matrix::Matrix::from_fn((3, 1), |j, _| {
+                (i % 3 == j) as u8 as f32
+            }))
+            .collect();
+        let preprocess = Preprocess::fit(&xs);
+
+        let train = |sparse: bool| {
+            let layer = Layer::new_rand(Act::Tanh, 8, 3, &mut rng::seeded(52));
+            let mut net = FFNet::new(Sequential::new(vec![Box::new(layer)]),
+                                     Box::new(Sgd),
+                                     Vec::new(),
+                                     1);
+            net.set_sparse_input(sparse);
+            net.set_preprocess(preprocess);
+            net.set_seed(7);
+            net.set_max_batches(4);
+            net.train(3, 0.1, (xs.clone(), ys.clone()));
+            net.layers.modules()[0].parameters()[0].clone()
+        };
+        assert_eq!(train(true).a, train(false).a);
+    }
 }
@@ -2,15 +2,11 @@
 
 extern crate mnist;
 use mnist::nets::*;
-
-use mnist::Matrix;
+use mnist::idx::read_idx;
 
 extern crate crossbeam;
 //use crossbeam;
 
-use std::fs::File;
-use std::io::Read;
-
 fn main()
 {
     let xs = read_idx("./res/train-images.idx3-ubyte", 40_000);
@@ -28,105 +24,30 @@ fn main()
     let num_cores = 8;
     let step = 0.006;
     let batch_size = 256;
-    //let step_decay = 0.96;
 
-    let af = AFunc{};
+    let af = Act::Relu;
+    let seed = 42;
+    let mut rng = mnist::rng::seeded(seed);
 
     let mut net = FFNet::new(
-            vec![
-                Layer::new_rand(af.clone(), 28 * 28, 200),
-                Layer::new_rand(af.clone(), 200, 20),
-                //Layer::new_rand(af.clone(), 100, 20),
-                Layer::new_rand(af.clone(), 20, 20),
-                Layer::new_rand(af.clone(), 20, 10)
-            ],
+            Sequential::new(vec![
+                Box::new(Layer::new_rand(af, 28 * 28, 200, &mut rng)),
+                Box::new(Layer::new_rand(af, 200, 20, &mut rng)),
+                //Box::new(Layer::new_rand(af, 100, 20, &mut rng)),
+                Box::new(Layer::new_rand(af, 20, 20, &mut rng)),
+                Box::new(Layer::new_rand(af, 20, 10, &mut rng))
+            ]),
+            Box::new(Sgd),
             test_set,
             num_cores);
+    net.set_schedule(Box::new(StepDecay::new(1000, 0.96)));
+    net.set_sparse_input(true);
+    net.set_batched(true);
+    net.set_seed(seed);
+    net.set_checkpoint("./res/mnist.net");
     net.train(batch_size, step, (xs, ys));
 }
 
-fn read_idx(fname: &str, num_vals: usize) -> Vec<Matrix<f32>>
-{
-    let f = File::open(fname).unwrap();
-    let mut bytes = f.bytes();
-
-    let magic_num = read_int(&mut bytes);
-    let dim = magic_num % (1 << 3);
-    let len = read_int(&mut bytes);
-
-    return match dim {
-        1 => {
-            let mut r = Vec::with_capacity(len);
-            while let Some(res) = bytes.next() {
-                if let Ok(b) = res {
-                    let mut v = vec![0.0; 10];
-                    v[b as usize] = 1.0;
-                    r.push(Matrix::with_vec((10, 1), v));
-                    if r.len() >= num_vals {
-                        break;
-                    }
-                } else {
-                    panic!("some error with reading the byte from the file");
-                }
-            }
-            r
-        }
-        3 => {
-            let (m, n) = (read_int(&mut bytes), read_int(&mut bytes));
-            let mut r = Vec::with_capacity(len);
-
-            let mut i = 0;
-            let mut v = Vec::with_capacity(m * n);
-
-            while let Some(res) = bytes.next() {
-                if !(i < m * n) {
-                    i = 0;
-                    r.push(Matrix::with_vec((m * n, 1), v));
-                    v = Vec::with_capacity(m * n);
-                    if r.len() >= num_vals {
-                        break;
-                    }
-                }
-                if let Ok(b) = res {
-                    v.push(b as f32);
-                } else {
-                    panic!("some error with reading the byte from the file");
-                }
-                i += 1;
-            }
-            r
-        }
-        _ => panic!("some problem with the dimension read from the idx file"),
-    };
-
-    fn print_num(m: &Matrix<f32>)
-    {
-        for (i, d) in m.a.iter().enumerate() {
-            if i % m.dim.0 == 0 {
-                println!("");
-            }
-            if *d > 200.0 {
-                print!("#");
-            } else if *d > 100.0 {
-                print!("o");
-            } else if *d > 0.0 {
-                print!(".");
-            } else {
-                print!(" ");
-            }
-        }
-    }
-
-    fn read_int(it: &mut std::io::Bytes<std::fs::File>) -> usize
-    {
-        let mut n: usize = 0;
-        for i in 0..4 {
-            n += (it.next().unwrap().unwrap() as usize) << ((3 - i) * 8);
-        }
-        n
-    }
-}
-
 // fn d_2_sec(t: Duration) -> f64
 // {
 //     let ns = (t.subsec_nanos() / 1000) as f64;
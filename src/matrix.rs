@@ -1,9 +1,22 @@
 use std::ops::{Add, Index, Mul, Sub};
 use std::cmp::PartialOrd;
+use std::fmt;
+use std::iter::StepBy;
+use std::slice::Iter;
 
 extern crate rand;
 use self::rand::Rng;
-use std::time::*;
+use self::rand::distributions::{IndependentSample, Normal};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Axis
+{
+    Rows,
+    Cols,
+}
+
+// one column of a matrix, see Matrix::cols.
+pub type Col<'a, T> = StepBy<Iter<'a, T>>;
 
 #[derive(Debug)]
 pub struct Matrix<T>
@@ -36,10 +49,10 @@ impl<T> Matrix<T>
         Matrix { dim, a: v }
     }
 
-    pub fn new_rand(dim:(usize, usize), low: T, high: T) -> Matrix<T>
+    pub fn new_rand<R: Rng>(dim:(usize, usize), low: T, high: T, rng: &mut R)
+                            -> Matrix<T>
     {
         let mut v = Vec::with_capacity(dim.0 * dim.1);
-        let mut rng = rand::thread_rng();
         for _ in 0..(dim.0 * dim.1) {
             v.push(rng.gen_range(low, high));
         }
@@ -48,13 +61,161 @@ impl<T> Matrix<T>
 
     pub fn new_const(dim:(usize, usize), c: T) -> Matrix<T>
     {
-        let mut v = Vec::with_capacity(dim.0 * dim.1);
-        for _ in 0..(dim.0 * dim.1) {
-            v.push(c);
+        Matrix::from_fn(dim, |_, _| c)
+    }
+
+    // builds the matrix with element (i, j) = f(i, j).
+    pub fn from_fn<F: FnMut(usize, usize) -> T>(dim: (usize, usize), mut f: F)
+                                                -> Matrix<T>
+    {
+        let (m, n) = dim;
+        let mut v = Vec::with_capacity(m * n);
+        for i in 0..m {
+            for j in 0..n {
+                v.push(f(i, j));
+            }
         }
         Matrix::with_vec(dim, v)
     }
 
+    pub fn map<F: Fn(T) -> T>(&self, f: F) -> Matrix<T>
+    {
+        Matrix::with_vec(self.dim, self.a.iter().map(|e| f(*e)).collect())
+    }
+
+    pub fn map_inplace<F: Fn(T) -> T>(&mut self, f: F)
+    {
+        for e in self.a.iter_mut() {
+            *e = f(*e);
+        }
+    }
+
+    pub fn zip_with<F: Fn(T, T) -> T>(&self, rhs: &Matrix<T>, f: F) -> Matrix<T>
+    {
+        assert!(
+            self.dim == rhs.dim,
+            "matrix dimensions are not compatable: self: {:?}, other: {:?}",
+            self.dim,
+            rhs.dim
+        );
+
+        Matrix::with_vec(self.dim,
+                         self.a
+                             .iter()
+                             .zip(rhs.a.iter())
+                             .map(|(x, y)| f(*x, *y))
+                             .collect())
+    }
+
+    // each row as a slice. an (m, 0) matrix still has m empty rows.
+    pub fn rows(&self) -> impl Iterator<Item = &[T]>
+    {
+        let n = self.dim.1;
+        (0..self.dim.0).map(move |i| &self.a[i * n..(i + 1) * n])
+    }
+
+    // each column as an iterator over its elements, top to bottom.
+    pub fn cols(&self) -> impl Iterator<Item = Col<'_, T>>
+    {
+        let n = self.dim.1;
+        // get, since an empty matrix has no element j to start from
+        (0..n).map(move |j| self.a.get(j..).unwrap_or(&[]).iter().step_by(n))
+    }
+
+    pub fn row(&self, i: usize) -> Matrix<T>
+    {
+        assert!(i < self.dim.0, "no row {} in a {:?} matrix", i, self.dim);
+        let n = self.dim.1;
+        Matrix::with_vec((1, n), self.a[i * n..(i + 1) * n].to_vec())
+    }
+
+    pub fn col(&self, j: usize) -> Matrix<T>
+    {
+        assert!(j < self.dim.1, "no column {} in a {:?} matrix", j, self.dim);
+        let n = self.dim.1;
+        Matrix::with_vec((self.dim.0, 1),
+                         self.a[j..].iter().step_by(n).cloned().collect())
+    }
+
+    // same elements in the same row major order, just a different shape.
+    pub fn reshape(&self, dim: (usize, usize)) -> Matrix<T>
+    {
+        assert!(dim.0 * dim.1 == self.len(),
+                "can't reshape {:?} into {:?}",
+                self.dim,
+                dim);
+        Matrix::with_vec(dim, self.a.clone())
+    }
+
+    // stacks matricies on top of each other (Axis::Rows) or side by side
+    // (Axis::Cols).
+    pub fn concat(ms: &[&Matrix<T>], axis: Axis) -> Matrix<T>
+    {
+        assert!(!ms.is_empty(), "need at least one matrix to concat");
+
+        match axis {
+            Axis::Rows => {
+                let n = ms[0].dim.1;
+                let mut v = Vec::new();
+                for m in ms {
+                    assert!(m.dim.1 == n,
+                            "can't stack a {:?} matrix under {} columns",
+                            m.dim,
+                            n);
+                    v.extend_from_slice(&m.a);
+                }
+                Matrix::with_vec((ms.iter().map(|e| e.dim.0).sum(), n), v)
+            }
+            Axis::Cols => {
+                let m = ms[0].dim.0;
+                let n = ms.iter().map(|e| e.dim.1).sum();
+                let mut v = Vec::with_capacity(m * n);
+                for e in ms {
+                    assert!(e.dim.0 == m,
+                            "can't put a {:?} matrix beside {} rows",
+                            e.dim,
+                            m);
+                }
+                for i in 0..m {
+                    for e in ms {
+                        let w = e.dim.1;
+                        v.extend_from_slice(&e.a[i * w..(i + 1) * w]);
+                    }
+                }
+                Matrix::with_vec((m, n), v)
+            }
+        }
+    }
+
+    pub fn vstack(ms: &[&Matrix<T>]) -> Matrix<T>
+    {
+        Matrix::concat(ms, Axis::Rows)
+    }
+
+    pub fn hstack(ms: &[&Matrix<T>]) -> Matrix<T>
+    {
+        Matrix::concat(ms, Axis::Cols)
+    }
+
+    // splits off the rows or columns before `at` from the rest.
+    pub fn split(&self, at: usize, axis: Axis) -> (Matrix<T>, Matrix<T>)
+    {
+        let (m, n) = self.dim;
+        match axis {
+            Axis::Rows => {
+                assert!(at <= m, "can't split {:?} at row {}", self.dim, at);
+                let (top, bottom) = self.a.split_at(at * n);
+                (Matrix::with_vec((at, n), top.to_vec()),
+                 Matrix::with_vec((m - at, n), bottom.to_vec()))
+            }
+            Axis::Cols => {
+                assert!(at <= n, "can't split {:?} at column {}", self.dim, at);
+                (Matrix::from_fn((m, at), |i, j| self.a[i * n + j]),
+                 Matrix::from_fn((m, n - at), |i, j| self.a[i * n + at + j]))
+            }
+        }
+    }
+
     pub fn add_by(&mut self, rhs: &Matrix<T>)
     {
         assert!(self.dim.0 == rhs.dim.0 && self.dim.1 == rhs.dim.1,
@@ -66,6 +227,36 @@ impl<T> Matrix<T>
         }
     }
 
+    // adds the (m, 1) column to every column of self, e.g. a bias to each
+    // sample in a batch.
+    pub fn add_col_by(&mut self, col: &Matrix<T>)
+    {
+        assert!(col.dim == (self.dim.0, 1),
+                "can't add a {:?} column to each column of {:?}",
+                col.dim,
+                self.dim);
+
+        let n = self.dim.1;
+        for (i, row) in self.a.chunks_mut(n.max(1)).enumerate() {
+            for e in row {
+                *e = *e + col.a[i];
+            }
+        }
+    }
+
+    // adds up the columns, giving an (m, 1) column.
+    pub fn sum_cols(&self) -> Matrix<T>
+    {
+        let sums = self.rows()
+                       .map(|row| {
+                           let mut it = row.iter();
+                           let first = *it.next().unwrap();
+                           it.fold(first, |acc, e| acc + *e)
+                       })
+                       .collect();
+        Matrix::with_vec((self.dim.0, 1), sums)
+    }
+
     pub fn h_prod(&self, rhs: &Matrix<T>) -> Matrix<T>
     {
         assert!(
@@ -75,12 +266,7 @@ impl<T> Matrix<T>
             rhs.dim
         );
 
-        let mut r = Matrix::new(self.dim);
-
-        for i in 0..self.a.len() {
-            r.a.push(self.a[i] * rhs.a[i]);
-        }
-        r
+        self.zip_with(rhs, |x, y| x * y)
     }
 
     pub fn t(&self) -> Matrix<T>
@@ -111,6 +297,11 @@ impl<T> Matrix<T>
         self.a.len()
     }
 
+    pub fn is_empty(&self) -> bool
+    {
+        self.a.is_empty()
+    }
+
     // equivalent to: self * (rhs^t)
     pub fn mul_tr(&self, rhs: &Self) -> Matrix<T>
     {
@@ -171,15 +362,11 @@ impl<T> Clone for Matrix<T>
 {
     fn clone(&self) -> Self
     {
-        let mut a1 = Matrix::new(self.dim);
-        for e in &self.a {
-            a1.a.push(*e);
-        }
-        a1
+        self.map(|e| e)
     }
 }
 
-impl<'a, 'b, T> Mul<&'b Matrix<T>> for &'a Matrix<T>
+impl<'b, T> Mul<&'b Matrix<T>> for &Matrix<T>
     where T: Mul<Output = T> + Add<Output = T> + Sub<Output = T> + Copy
         + rand::distributions::range::SampleRange
         + PartialOrd
@@ -210,7 +397,7 @@ impl<'a, 'b, T> Mul<&'b Matrix<T>> for &'a Matrix<T>
     }
 }
 
-impl<'a, T> Mul<T> for &'a Matrix<T>
+impl<T> Mul<T> for &Matrix<T>
     where T: Mul<Output = T> + Add<Output = T> + Sub<Output = T> + Copy
         + rand::distributions::range::SampleRange
         + PartialOrd
@@ -219,11 +406,11 @@ impl<'a, T> Mul<T> for &'a Matrix<T>
 
     fn mul(self, rhs: T) -> Matrix<T>
     {
-        Matrix::with_vec(self.dim, self.a.iter().map(|e| *e * rhs).collect())
+        self.map(|e| e * rhs)
     }
 }
 
-impl<'a, 'b, T> Add<&'b Matrix<T>> for &'a Matrix<T>
+impl<'b, T> Add<&'b Matrix<T>> for &Matrix<T>
     where T: Mul<Output = T> + Add<Output = T> + Sub<Output = T> + Copy
         + rand::distributions::range::SampleRange
         + PartialOrd
@@ -248,7 +435,7 @@ impl<'a, 'b, T> Add<&'b Matrix<T>> for &'a Matrix<T>
     }
 }
 
-impl<'a, 'b, T> Sub<&'b Matrix<T>> for &'a Matrix<T>
+impl<'b, T> Sub<&'b Matrix<T>> for &Matrix<T>
     where T: Mul<Output = T> + Add<Output = T> + Sub<Output = T> + Copy
         + rand::distributions::range::SampleRange
         + PartialOrd
@@ -283,3 +470,129 @@ impl<T> Index<usize> for Matrix<T>
         &self.a[i]
     }
 }
+
+// matrices bigger than this along a dimension get the middle elided when
+// displayed, keeping EDGE rows/columns on either side.
+const MAX_SHOWN: usize = 12;
+const EDGE: usize = 4;
+
+// indices to show along a dimension of length n, None marks the elision.
+fn shown(n: usize) -> Vec<Option<usize>>
+{
+    if n <= MAX_SHOWN {
+        (0..n).map(Some).collect()
+    } else {
+        (0..EDGE).map(Some)
+                 .chain(Some(None))
+                 .chain((n - EDGE..n).map(Some))
+                 .collect()
+    }
+}
+
+impl<T> fmt::Display for Matrix<T>
+    where T: Mul<Output = T> + Add<Output = T> + Sub<Output = T> + Copy
+        + fmt::Display
+{
+    // prints one line per row with the columns right aligned. the precision
+    // of the formatter is used for the elements, so `{:.2}` works.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        let (m, n) = self.dim;
+        let prec = f.precision().unwrap_or(4);
+        let cols = shown(n);
+
+        let cells: Vec<Vec<String>> = shown(m)
+            .iter()
+            .map(|i| {
+                cols.iter()
+                    .map(|j| match (*i, *j) {
+                        (Some(i), Some(j)) => {
+                            format!("{:.*}", prec, self.a[i * n + j])
+                        }
+                        _ => "...".to_string(),
+                    })
+                    .collect()
+            })
+            .collect();
+
+        let widths: Vec<usize> = (0..cols.len())
+            .map(|j| cells.iter().map(|r| r[j].len()).max().unwrap_or(0))
+            .collect();
+
+        writeln!(f, "Matrix {}x{}", m, n)?;
+        for (i, row) in cells.iter().enumerate() {
+            let open = if i == 0 { "[" } else { " " };
+            let close = if i + 1 == cells.len() { "]" } else { "" };
+            write!(f, "{}[", open)?;
+            for (j, cell) in row.iter().enumerate() {
+                if j > 0 {
+                    write!(f, " ")?;
+                }
+                write!(f, "{:>w$}", cell, w = widths[j])?;
+            }
+            writeln!(f, "]{}", close)?;
+        }
+        Ok(())
+    }
+}
+
+pub const ASCII_SHADES: &[char] = &[' ', '.', ':', 'o', 'O', '#'];
+pub const BLOCK_SHADES: &[char] = &[' ', '\u{2591}', '\u{2592}', '\u{2593}',
+                                    '\u{2588}'];
+
+impl Matrix<f32>
+{
+    pub fn new_normal<R: Rng>(dim: (usize, usize),
+                              mean: f32,
+                              std: f32,
+                              rng: &mut R)
+                              -> Matrix<f32>
+    {
+        let normal = Normal::new(mean as f64, std as f64);
+        Matrix::from_fn(dim, |_, _| normal.ind_sample(rng) as f32)
+    }
+
+    // like new_normal, but anything more than 2 std from the mean gets
+    // drawn again.
+    pub fn new_truncated_normal<R: Rng>(dim: (usize, usize),
+                                        mean: f32,
+                                        std: f32,
+                                        rng: &mut R)
+                                        -> Matrix<f32>
+    {
+        let normal = Normal::new(0.0, 1.0);
+        Matrix::from_fn(dim, |_, _| loop {
+            let z = normal.ind_sample(rng) as f32;
+            if z.abs() <= 2.0 {
+                break mean + std * z;
+            }
+        })
+    }
+
+    // draws a (h * w, 1) image as h lines of `width` characters. each pixel
+    // picks a shade by where it falls between the image's min and max, so it
+    // works for raw 0-255 pixels as well as normalized inputs.
+    pub fn render(&self, width: usize, shades: &[char]) -> String
+    {
+        assert!(self.dim.1 == 1 && width > 0 && self.len().is_multiple_of(width),
+                "can't render a {:?} matrix as an image {} pixels wide",
+                self.dim,
+                width);
+        assert!(!shades.is_empty(), "need at least one shade to render");
+
+        let lo = self.a.iter().cloned().fold(f32::INFINITY, f32::min);
+        let hi = self.a.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+        let range = if hi > lo { hi - lo } else { 1.0 };
+        let top = (shades.len() - 1) as f32;
+
+        let mut s = String::with_capacity(self.len() + self.len() / width);
+        for row in self.a.chunks(width) {
+            for p in row {
+                let level = ((p - lo) / range * top).round() as usize;
+                s.push(shades[level.min(shades.len() - 1)]);
+            }
+            s.push('\n');
+        }
+        s
+    }
+}
@@ -0,0 +1,411 @@
+use nets::*;
+use std::fmt;
+use std::str::FromStr;
+
+pub struct ATan {}
+
+impl Activation<f32> for ATan
+{
+    fn f(&self, x: f32) -> f32
+    {
+        f32::atan(x)
+    }
+
+    fn df(&self, x: f32) -> f32
+    {
+        1.0 / (1.0 + x * x)
+    }
+
+    fn clone(&self) -> Self
+    {
+        ATan{}
+    }
+}
+
+// df at the kink is taken to be 0, so dead units stay dead.
+pub struct RELU {}
+
+impl Activation<Number> for RELU
+{
+    fn f(&self, x: f32) -> f32
+    {
+        if x > 0.0 { x } else { 0.0 }
+    }
+
+    fn df(&self, x: f32) -> f32
+    {
+        if x > 0.0 { 1.0 } else { 0.0 }
+    }
+
+    fn clone(&self) -> Self
+    {
+        RELU{}
+    }
+}
+
+pub struct Sigmoid {}
+
+fn sigmoid(x: f32) -> f32
+{
+    1.0 / (1.0 + (-x).exp())
+}
+
+impl Activation<Number> for Sigmoid
+{
+    fn f(&self, x: f32) -> f32
+    {
+        sigmoid(x)
+    }
+
+    fn df(&self, x: f32) -> f32
+    {
+        let s = sigmoid(x);
+        s * (1.0 - s)
+    }
+
+    fn clone(&self) -> Self
+    {
+        Sigmoid{}
+    }
+}
+
+pub struct Tanh {}
+
+impl Activation<Number> for Tanh
+{
+    fn f(&self, x: f32) -> f32
+    {
+        x.tanh()
+    }
+
+    fn df(&self, x: f32) -> f32
+    {
+        let t = x.tanh();
+        1.0 - t * t
+    }
+
+    fn clone(&self) -> Self
+    {
+        Tanh{}
+    }
+}
+
+// x for x > 0, slope * x otherwise.
+pub struct LeakyRELU
+{
+    pub slope: f32,
+}
+
+impl Activation<Number> for LeakyRELU
+{
+    fn f(&self, x: f32) -> f32
+    {
+        if x > 0.0 { x } else { self.slope * x }
+    }
+
+    fn df(&self, x: f32) -> f32
+    {
+        if x > 0.0 { 1.0 } else { self.slope }
+    }
+
+    fn clone(&self) -> Self
+    {
+        LeakyRELU { slope: self.slope }
+    }
+}
+
+// x for x > 0, alpha * (e^x - 1) otherwise.
+pub struct ELU
+{
+    pub alpha: f32,
+}
+
+impl Activation<Number> for ELU
+{
+    fn f(&self, x: f32) -> f32
+    {
+        if x > 0.0 { x } else { self.alpha * x.exp_m1() }
+    }
+
+    fn df(&self, x: f32) -> f32
+    {
+        if x > 0.0 { 1.0 } else { self.alpha * x.exp() }
+    }
+
+    fn clone(&self) -> Self
+    {
+        ELU { alpha: self.alpha }
+    }
+}
+
+// the usual tanh approximation, std has no erf.
+pub struct GELU {}
+
+const GELU_C: f32 = 0.797_884_6; // sqrt(2 / pi)
+const GELU_K: f32 = 0.044_715;
+
+impl Activation<Number> for GELU
+{
+    fn f(&self, x: f32) -> f32
+    {
+        let t = (GELU_C * (x + GELU_K * x * x * x)).tanh();
+        0.5 * x * (1.0 + t)
+    }
+
+    fn df(&self, x: f32) -> f32
+    {
+        let t = (GELU_C * (x + GELU_K * x * x * x)).tanh();
+        let dt = (1.0 - t * t) * GELU_C * (1.0 + 3.0 * GELU_K * x * x);
+        0.5 * (1.0 + t) + 0.5 * x * dt
+    }
+
+    fn clone(&self) -> Self
+    {
+        GELU{}
+    }
+}
+
+// ln(1 + e^x), written so big x doesn't overflow.
+pub struct Softplus {}
+
+impl Activation<Number> for Softplus
+{
+    fn f(&self, x: f32) -> f32
+    {
+        x.max(0.0) + (-x.abs()).exp().ln_1p()
+    }
+
+    fn df(&self, x: f32) -> f32
+    {
+        sigmoid(x)
+    }
+
+    fn clone(&self) -> Self
+    {
+        Softplus{}
+    }
+}
+
+// x * sigmoid(x)
+pub struct Swish {}
+
+impl Activation<Number> for Swish
+{
+    fn f(&self, x: f32) -> f32
+    {
+        x * sigmoid(x)
+    }
+
+    fn df(&self, x: f32) -> f32
+    {
+        let s = sigmoid(x);
+        s + x * s * (1.0 - s)
+    }
+
+    fn clone(&self) -> Self
+    {
+        Swish{}
+    }
+}
+
+/*
+ * leaky relu where the slope for negative inputs is learned. alpha is a
+ * (1, 1) matrix to share one slope across the layer, or (n, 1) for one per
+ * input row (channel).
+ */
+pub struct PReLU
+{
+    pub alpha: Matrix<Number>,
+}
+
+impl PReLU
+{
+    pub fn new(channels: usize, init: Number) -> PReLU
+    {
+        PReLU { alpha: Matrix::new_const((channels, 1), init) }
+    }
+
+    fn slope(&self, row: usize) -> Number
+    {
+        if self.alpha.len() == 1 { self.alpha.a[0] } else { self.alpha.a[row] }
+    }
+}
+
+impl Activation<Number> for PReLU
+{
+    fn f(&self, x: f32) -> f32
+    {
+        self.f_at(0, x)
+    }
+
+    fn df(&self, x: f32) -> f32
+    {
+        self.df_at(0, x)
+    }
+
+    fn clone(&self) -> Self
+    {
+        PReLU { alpha: self.alpha.clone() }
+    }
+
+    fn f_at(&self, row: usize, x: f32) -> f32
+    {
+        if x > 0.0 { x } else { self.slope(row) * x }
+    }
+
+    fn df_at(&self, row: usize, x: f32) -> f32
+    {
+        if x > 0.0 { 1.0 } else { self.slope(row) }
+    }
+
+    fn params(&self) -> Vec<&Matrix<Number>>
+    {
+        vec![&self.alpha]
+    }
+
+    fn params_mut(&mut self) -> Vec<&mut Matrix<Number>>
+    {
+        vec![&mut self.alpha]
+    }
+
+    // d f / d alpha is x where x <= 0, summed over everything sharing it.
+    fn param_grads(&self, x: &Matrix<Number>, g: &Matrix<Number>)
+                   -> Vec<Matrix<Number>>
+    {
+        let mut d = Matrix::new_const(self.alpha.dim, 0.0);
+        let shared = self.alpha.len() == 1;
+        for (i, (xr, gr)) in x.rows().zip(g.rows()).enumerate() {
+            let k = if shared { 0 } else { i };
+            for (xe, ge) in xr.iter().zip(gr.iter()) {
+                if *xe <= 0.0 {
+                    d.a[k] += ge * xe;
+                }
+            }
+        }
+        vec![d]
+    }
+}
+
+/*
+ * any of the stateless ones above, picked at runtime. each layer can have
+ * its own, e.g. "leaky_relu:0.1".parse::<Act>(). PReLU carries parameters,
+ * so it has to be used as a Layer<PReLU> or Conv2d<PReLU> directly.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Act
+{
+    ATan,
+    Relu,
+    Sigmoid,
+    Tanh,
+    LeakyRelu(f32),
+    Elu(f32),
+    Gelu,
+    Softplus,
+    Swish,
+}
+
+impl Act
+{
+    pub fn all() -> Vec<Act>
+    {
+        vec![Act::ATan,
+             Act::Relu,
+             Act::Sigmoid,
+             Act::Tanh,
+             Act::LeakyRelu(0.01),
+             Act::Elu(1.0),
+             Act::Gelu,
+             Act::Softplus,
+             Act::Swish]
+    }
+}
+
+impl Activation<Number> for Act
+{
+    fn f(&self, x: f32) -> f32
+    {
+        match *self {
+            Act::ATan => ATan {}.f(x),
+            Act::Relu => RELU {}.f(x),
+            Act::Sigmoid => Sigmoid {}.f(x),
+            Act::Tanh => Tanh {}.f(x),
+            Act::LeakyRelu(slope) => LeakyRELU { slope }.f(x),
+            Act::Elu(alpha) => ELU { alpha }.f(x),
+            Act::Gelu => GELU {}.f(x),
+            Act::Softplus => Softplus {}.f(x),
+            Act::Swish => Swish {}.f(x),
+        }
+    }
+
+    fn df(&self, x: f32) -> f32
+    {
+        match *self {
+            Act::ATan => ATan {}.df(x),
+            Act::Relu => RELU {}.df(x),
+            Act::Sigmoid => Sigmoid {}.df(x),
+            Act::Tanh => Tanh {}.df(x),
+            Act::LeakyRelu(slope) => LeakyRELU { slope }.df(x),
+            Act::Elu(alpha) => ELU { alpha }.df(x),
+            Act::Gelu => GELU {}.df(x),
+            Act::Softplus => Softplus {}.df(x),
+            Act::Swish => Swish {}.df(x),
+        }
+    }
+
+    fn clone(&self) -> Self
+    {
+        *self
+    }
+}
+
+impl fmt::Display for Act
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match *self {
+            Act::ATan => write!(f, "atan"),
+            Act::Relu => write!(f, "relu"),
+            Act::Sigmoid => write!(f, "sigmoid"),
+            Act::Tanh => write!(f, "tanh"),
+            Act::LeakyRelu(slope) => write!(f, "leaky_relu:{}", slope),
+            Act::Elu(alpha) => write!(f, "elu:{}", alpha),
+            Act::Gelu => write!(f, "gelu"),
+            Act::Softplus => write!(f, "softplus"),
+            Act::Swish => write!(f, "swish"),
+        }
+    }
+}
+
+// parses what Display prints. the parameter of leaky_relu and elu can be
+// left off to get 0.01 and 1.0.
+impl FromStr for Act
+{
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Act, String>
+    {
+        let mut parts = s.splitn(2, ':');
+        let name = parts.next().unwrap_or("");
+        let param = match parts.next() {
+            Some(p) => {
+                Some(p.parse::<f32>()
+                      .map_err(|_| format!("bad parameter in {:?}", s))?)
+            }
+            None => None,
+        };
+
+        match (name, param) {
+            ("atan", None) => Ok(Act::ATan),
+            ("relu", None) => Ok(Act::Relu),
+            ("sigmoid", None) => Ok(Act::Sigmoid),
+            ("tanh", None) => Ok(Act::Tanh),
+            ("leaky_relu", p) => Ok(Act::LeakyRelu(p.unwrap_or(0.01))),
+            ("elu", p) => Ok(Act::Elu(p.unwrap_or(1.0))),
+            ("gelu", None) => Ok(Act::Gelu),
+            ("softplus", None) => Ok(Act::Softplus),
+            ("swish", None) => Ok(Act::Swish),
+            _ => Err(format!("unknown activation {:?}", s)),
+        }
+    }
+}
@@ -0,0 +1,242 @@
+use nets::*;
+
+extern crate crossbeam;
+
+/*
+ * the arithmetic layers need, so the kernels can be swapped out per net.
+ * Reference just calls the naive Matrix methods and is what the others get
+ * checked against.
+ */
+pub trait Backend: Send + Sync
+{
+    fn name(&self) -> &'static str;
+
+    // a * b
+    fn mul(&self, a: &Matrix<Number>, b: &Matrix<Number>) -> Matrix<Number>;
+    // a * (b^t)
+    fn mul_tr(&self, a: &Matrix<Number>, b: &Matrix<Number>) -> Matrix<Number>;
+    // (a^t) * b
+    fn mul_tl(&self, a: &Matrix<Number>, b: &Matrix<Number>) -> Matrix<Number>;
+
+    fn add_by(&self, a: &mut Matrix<Number>, b: &Matrix<Number>);
+    fn add_col_by(&self, a: &mut Matrix<Number>, col: &Matrix<Number>);
+    fn h_prod(&self, a: &Matrix<Number>, b: &Matrix<Number>) -> Matrix<Number>;
+    // applies f to every element, e.g. an activation function
+    fn map(&self, x: &mut Matrix<Number>, f: &dyn Fn(Number) -> Number);
+
+    // like map, but f also gets the row of each element.
+    fn map_rows(&self,
+                x: &mut Matrix<Number>,
+                f: &dyn Fn(usize, Number) -> Number)
+    {
+        let n = x.dim.1.max(1);
+        for (i, row) in x.a.chunks_mut(n).enumerate() {
+            for e in row {
+                *e = f(i, *e);
+            }
+        }
+    }
+
+    fn sum_cols(&self, x: &Matrix<Number>) -> Matrix<Number>;
+    fn sum(&self, x: &Matrix<Number>) -> Number;
+}
+
+pub struct Reference;
+
+impl Backend for Reference
+{
+    fn name(&self) -> &'static str
+    {
+        "reference"
+    }
+
+    fn mul(&self, a: &Matrix<Number>, b: &Matrix<Number>) -> Matrix<Number>
+    {
+        a * b
+    }
+
+    fn mul_tr(&self, a: &Matrix<Number>, b: &Matrix<Number>) -> Matrix<Number>
+    {
+        a.mul_tr(b)
+    }
+
+    fn mul_tl(&self, a: &Matrix<Number>, b: &Matrix<Number>) -> Matrix<Number>
+    {
+        a.mul_tl(b)
+    }
+
+    fn add_by(&self, a: &mut Matrix<Number>, b: &Matrix<Number>)
+    {
+        a.add_by(b);
+    }
+
+    fn add_col_by(&self, a: &mut Matrix<Number>, col: &Matrix<Number>)
+    {
+        a.add_col_by(col);
+    }
+
+    fn h_prod(&self, a: &Matrix<Number>, b: &Matrix<Number>) -> Matrix<Number>
+    {
+        a.h_prod(b)
+    }
+
+    fn map(&self, x: &mut Matrix<Number>, f: &dyn Fn(Number) -> Number)
+    {
+        x.map_inplace(f);
+    }
+
+    fn sum_cols(&self, x: &Matrix<Number>) -> Matrix<Number>
+    {
+        x.sum_cols()
+    }
+
+    fn sum(&self, x: &Matrix<Number>) -> Number
+    {
+        x.a.iter().sum()
+    }
+}
+
+/*
+ * splits the rows of every product across threads and orders the loops so
+ * the inner one walks memory contiguously. the elementwise ops are memory
+ * bound, so they stay on the calling thread.
+ */
+pub struct Threaded
+{
+    threads: usize,
+}
+
+impl Threaded
+{
+    pub fn new(threads: usize) -> Threaded
+    {
+        assert!(threads > 0);
+        Threaded { threads }
+    }
+
+    // fills the (m, n) result by handing each thread a run of rows. f gets
+    // the index of the first row and the slice it has to fill.
+    fn par_rows<F>(&self, dim: (usize, usize), f: F) -> Matrix<Number>
+        where F: Fn(usize, &mut [Number]) + Sync
+    {
+        let (m, n) = dim;
+        let mut out = vec![0.0; m * n];
+        let rows_per_thread = m.div_ceil(self.threads).max(1);
+
+        if n > 0 {
+            let f = &f;
+            crossbeam::scope(|scope| {
+                for (t, rows) in out.chunks_mut(rows_per_thread * n)
+                                    .enumerate()
+                {
+                    scope.spawn(move || f(t * rows_per_thread, rows));
+                }
+            });
+        }
+        Matrix::with_vec(dim, out)
+    }
+}
+
+impl Backend for Threaded
+{
+    fn name(&self) -> &'static str
+    {
+        "threaded"
+    }
+
+    fn mul(&self, a: &Matrix<Number>, b: &Matrix<Number>) -> Matrix<Number>
+    {
+        let (m, k) = a.dim;
+        let (k1, n) = b.dim;
+        assert!(k == k1,
+                "can't do a*b with dimensions, {:?} and {:?}",
+                a.dim,
+                b.dim);
+
+        self.par_rows((m, n), |start, out| {
+            for (r, row) in out.chunks_mut(n).enumerate() {
+                let i = start + r;
+                for p in 0..k {
+                    let aip = a.a[i * k + p];
+                    let bp = &b.a[p * n..(p + 1) * n];
+                    for (e, bpj) in row.iter_mut().zip(bp) {
+                        *e += aip * bpj;
+                    }
+                }
+            }
+        })
+    }
+
+    fn mul_tr(&self, a: &Matrix<Number>, b: &Matrix<Number>) -> Matrix<Number>
+    {
+        let (m, k) = a.dim;
+        let (n, k1) = b.dim;
+        assert!(k == k1,
+                "can't do a*(b^t) with dimensions, {:?} and {:?}",
+                a.dim,
+                b.dim);
+
+        self.par_rows((m, n), |start, out| {
+            for (r, row) in out.chunks_mut(n).enumerate() {
+                let ai = &a.a[(start + r) * k..(start + r + 1) * k];
+                for (j, e) in row.iter_mut().enumerate() {
+                    let bj = &b.a[j * k..(j + 1) * k];
+                    *e = ai.iter().zip(bj).map(|(x, y)| x * y).sum();
+                }
+            }
+        })
+    }
+
+    fn mul_tl(&self, a: &Matrix<Number>, b: &Matrix<Number>) -> Matrix<Number>
+    {
+        let (k, m) = a.dim;
+        let (k1, n) = b.dim;
+        assert!(k == k1,
+                "can't do (a^t)*b with dimensions, {:?} and {:?}",
+                a.dim,
+                b.dim);
+
+        self.par_rows((m, n), |start, out| {
+            for (r, row) in out.chunks_mut(n).enumerate() {
+                let i = start + r;
+                for p in 0..k {
+                    let api = a.a[p * m + i];
+                    let bp = &b.a[p * n..(p + 1) * n];
+                    for (e, bpj) in row.iter_mut().zip(bp) {
+                        *e += api * bpj;
+                    }
+                }
+            }
+        })
+    }
+
+    fn add_by(&self, a: &mut Matrix<Number>, b: &Matrix<Number>)
+    {
+        a.add_by(b);
+    }
+
+    fn add_col_by(&self, a: &mut Matrix<Number>, col: &Matrix<Number>)
+    {
+        a.add_col_by(col);
+    }
+
+    fn h_prod(&self, a: &Matrix<Number>, b: &Matrix<Number>) -> Matrix<Number>
+    {
+        a.h_prod(b)
+    }
+
+    fn map(&self, x: &mut Matrix<Number>, f: &dyn Fn(Number) -> Number)
+    {
+        x.map_inplace(f);
+    }
+
+    fn sum_cols(&self, x: &Matrix<Number>) -> Matrix<Number>
+    {
+        x.sum_cols()
+    }
+
+    fn sum(&self, x: &Matrix<Number>) -> Number
+    {
+        x.a.iter().sum()
+    }
+}
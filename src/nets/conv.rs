@@ -0,0 +1,622 @@
+use nets::*;
+use std::any::Any;
+use std::sync::Mutex;
+
+/*
+ * image layers. an image is still one column, its channels one after the
+ * other and each of those row by row, so a (c, h, w) image is a
+ * (c * h * w, 1) column and these stack into batches and mix with Layer just
+ * like any other input.
+ */
+
+// where a kernel slides over a (c, h, w) input.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Window
+{
+    c: usize,
+    h: usize,
+    w: usize,
+    k: usize,
+    stride: usize,
+    pad: usize,
+    oh: usize,
+    ow: usize,
+}
+
+impl Window
+{
+    fn new(input: (usize, usize, usize), k: usize, stride: usize, pad: usize)
+           -> Window
+    {
+        let (c, h, w) = input;
+        assert!(stride > 0, "stride has to be positive");
+        assert!(h + 2 * pad >= k && w + 2 * pad >= k,
+                "a {}x{} kernel doesn't fit a {}x{} input padded by {}",
+                k,
+                k,
+                h,
+                w,
+                pad);
+        Window { c,
+                 h,
+                 w,
+                 k,
+                 stride,
+                 pad,
+                 oh: (h + 2 * pad - k) / stride + 1,
+                 ow: (w + 2 * pad - k) / stride + 1 }
+    }
+
+    fn input_shape(&self) -> (usize, usize, usize)
+    {
+        (self.c, self.h, self.w)
+    }
+
+    fn in_len(&self) -> usize
+    {
+        self.c * self.h * self.w
+    }
+
+    fn out_len(&self) -> usize
+    {
+        self.oh * self.ow
+    }
+
+    // the row in the input column under kernel offset (ki, kj) of channel
+    // ch at output position (oi, oj), or None when that's padding.
+    fn at(&self, ch: usize, oi: usize, oj: usize, ki: usize, kj: usize)
+          -> Option<usize>
+    {
+        let i = (oi * self.stride + ki).checked_sub(self.pad)?;
+        let j = (oj * self.stride + kj).checked_sub(self.pad)?;
+        if i < self.h && j < self.w {
+            Some((ch * self.h + i) * self.w + j)
+        } else {
+            None
+        }
+    }
+
+    /*
+     * im2col: one column per output position of every sample, holding the
+     * c * k * k inputs under the kernel there, so the whole convolution is
+     * a single product with the (out channels, c * k * k) kernels.
+     * sample b's positions are columns b * out_len.. of the result.
+     */
+    fn im2col(&self, x: &Matrix<Number>) -> Matrix<Number>
+    {
+        let (batch, n) = (x.dim.1, self.out_len());
+        let cols = batch * n;
+        let mut out = Matrix::new_const((self.c * self.k * self.k, cols), 0.0);
+
+        for ch in 0..self.c {
+            for ki in 0..self.k {
+                for kj in 0..self.k {
+                    let row = (ch * self.k + ki) * self.k + kj;
+                    for oi in 0..self.oh {
+                        for oj in 0..self.ow {
+                            let src = match self.at(ch, oi, oj, ki, kj) {
+                                Some(src) => src,
+                                None => continue,
+                            };
+                            let p = oi * self.ow + oj;
+                            for b in 0..batch {
+                                out.a[row * cols + b * n + p] =
+                                    x.a[src * batch + b];
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    // the reverse of im2col, summing everywhere windows overlap.
+    fn col2im(&self, cols: &Matrix<Number>, batch: usize) -> Matrix<Number>
+    {
+        let (n, width) = (self.out_len(), cols.dim.1);
+        let mut x = Matrix::new_const((self.in_len(), batch), 0.0);
+
+        for ch in 0..self.c {
+            for ki in 0..self.k {
+                for kj in 0..self.k {
+                    let row = (ch * self.k + ki) * self.k + kj;
+                    for oi in 0..self.oh {
+                        for oj in 0..self.ow {
+                            let dst = match self.at(ch, oi, oj, ki, kj) {
+                                Some(dst) => dst,
+                                None => continue,
+                            };
+                            let p = oi * self.ow + oj;
+                            for b in 0..batch {
+                                x.a[dst * batch + b] +=
+                                    cols.a[row * width + b * n + p];
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        x
+    }
+}
+
+// (channels, b * n) with sample b's n positions side by side, to one
+// (channels * n, b) column per sample, and back.
+fn positions_to_columns(y: &Matrix<Number>, n: usize) -> Matrix<Number>
+{
+    let (ch, batch) = (y.dim.0, y.dim.1 / n);
+    Matrix::from_fn((ch * n, batch), |r, b| {
+        let (o, p) = (r / n, r % n);
+        y.a[o * y.dim.1 + b * n + p]
+    })
+}
+
+fn columns_to_positions(g: &Matrix<Number>, n: usize) -> Matrix<Number>
+{
+    let (ch, batch) = (g.dim.0 / n, g.dim.1);
+    Matrix::from_fn((ch, batch * n), |o, q| {
+        let (b, p) = (q / n, q % n);
+        g.a[(o * n + p) * batch + b]
+    })
+}
+
+/*
+ * a 2d convolution of f(x), like Layer but with each of the out channels'
+ * k by k kernels shared across every position of the image.
+ */
+pub struct Conv2d<A: Activation<Number>>
+{
+    pub w: Matrix<Number>,
+    b: Matrix<Number>,
+    activation: A,
+    win: Window,
+    grad: Mutex<ConvGrads>,
+}
+
+struct ConvGrads
+{
+    w: Matrix<Number>,
+    b: Matrix<Number>,
+    // for the activation's own params, if it has any
+    act: Vec<Matrix<Number>>,
+}
+
+impl<A: Activation<Number>> Conv2d<A>
+{
+    // input is (channels, height, width), the kernels are kernel by kernel.
+    pub fn new_rand<R: Rng>(activation: A,
+                            input: (usize, usize, usize),
+                            out_channels: usize,
+                            kernel: usize,
+                            stride: usize,
+                            padding: usize,
+                            rng: &mut R)
+                            -> Conv2d<A>
+    {
+        let win = Window::new(input, kernel, stride, padding);
+        // the activation maps the input rows, like in Layer
+        for p in activation.params() {
+            assert!(p.dim.0 == 1 || p.dim.0 == win.in_len(),
+                    "activation has {} params for {} inputs",
+                    p.dim.0,
+                    win.in_len());
+        }
+        let act = activation.params()
+                            .iter()
+                            .map(|p| Matrix::new_const(p.dim, 0.0))
+                            .collect();
+        let fan_in = input.0 * kernel * kernel;
+        let w_max = 1.0 / (fan_in as Number).sqrt();
+        let dim = (out_channels, fan_in);
+        Conv2d { w: Matrix::new_rand(dim, -w_max, w_max, rng),
+                 b: Matrix::new_const((out_channels, 1), 0.01),
+                 activation,
+                 win,
+                 grad: Mutex::new(ConvGrads {
+                     w: Matrix::new_const(dim, 0.0),
+                     b: Matrix::new_const((out_channels, 1), 0.0),
+                     act,
+                 }) }
+    }
+
+    pub fn b(&self) -> &Matrix<Number>
+    {
+        &self.b
+    }
+
+    // draws new kernels and biases, e.g. Init::HeNormal for relus. each
+    // input reaches k * k positions of every out channel, so that's the
+    // fan out, not the number of out channels.
+    pub fn initialize<R: Rng>(&mut self, weights: Init, bias: Init, rng: &mut R)
+    {
+        let (out, fan_in) = self.w.dim;
+        let area = self.win.k * self.win.k;
+        self.w = weights.with_fans(self.w.dim, fan_in, out * area, rng);
+        self.b = bias.bias(out, rng);
+    }
+
+    // (channels, height, width) of the output.
+    pub fn out_shape(&self) -> (usize, usize, usize)
+    {
+        (self.w.dim.0, self.win.oh, self.win.ow)
+    }
+
+    pub fn input_shape(&self) -> (usize, usize, usize)
+    {
+        self.win.input_shape()
+    }
+
+    pub fn kernel(&self) -> usize
+    {
+        self.win.k
+    }
+
+    pub fn stride(&self) -> usize
+    {
+        self.win.stride
+    }
+
+    pub fn padding(&self) -> usize
+    {
+        self.win.pad
+    }
+
+    pub fn activation(&self) -> &A
+    {
+        &self.activation
+    }
+}
+
+impl<A> Module for Conv2d<A>
+    where A: Activation<Number> + Send + Sync + 'static
+{
+    fn name(&self) -> String
+    {
+        let (c, h, w) = self.out_shape();
+        format!("conv {}x{}x{} -> {}x{}x{}",
+                self.win.c,
+                self.win.h,
+                self.win.w,
+                c,
+                h,
+                w)
+    }
+
+    fn forward(&self, x: Matrix<Number>, cache: &mut Cache, pass: &mut Pass)
+               -> Matrix<Number>
+    {
+        assert!(x.dim.0 == self.win.in_len(),
+                "{} got {} rows",
+                self.name(),
+                x.dim.0);
+        let mut fx = x.clone();
+        pass.be.map_rows(&mut fx, &|i, e| self.activation.f_at(i, e));
+        let cols = self.win.im2col(&fx);
+
+        let mut y = pass.be.mul(&self.w, &cols);
+        pass.be.add_col_by(&mut y, &self.b);
+
+        cache.push(x);
+        cache.push(cols);
+        positions_to_columns(&y, self.win.out_len())
+    }
+
+    fn backward(&self,
+                grad: Matrix<Number>,
+                cache: &mut Cache,
+                pass: &mut Pass)
+                -> Matrix<Number>
+    {
+        let cols = cache.pop();
+        let mut x = cache.pop();
+        let be = pass.be;
+        let g = columns_to_positions(&grad, self.win.out_len());
+
+        let d_w = be.mul_tr(&g, &cols);
+        let d_b = be.sum_cols(&g);
+        let d_fx = self.win.col2im(&be.mul_tl(&self.w, &g), x.dim.1);
+        let d_act = self.activation.param_grads(&x, &d_fx);
+
+        be.map_rows(&mut x, &|i, e| self.activation.df_at(i, e));
+        let d_x = be.h_prod(&d_fx, &x);
+
+        let mut acc = self.grad.lock().unwrap();
+        be.add_by(&mut acc.w, &d_w);
+        be.add_by(&mut acc.b, &d_b);
+        for (acc, d) in acc.act.iter_mut().zip(&d_act) {
+            be.add_by(acc, d);
+        }
+        d_x
+    }
+
+    fn parameters(&self) -> Vec<&Matrix<Number>>
+    {
+        let mut p = vec![&self.w, &self.b];
+        p.extend(self.activation.params());
+        p
+    }
+
+    fn gradients(&self) -> Vec<Matrix<Number>>
+    {
+        let grad = self.grad.lock().unwrap();
+        let mut g = vec![grad.w.clone(), grad.b.clone()];
+        g.extend(grad.act.iter().cloned());
+        g
+    }
+
+    fn params_and_grads(&mut self) -> Vec<(&mut Matrix<Number>,
+                                           &mut Matrix<Number>)>
+    {
+        let grad = self.grad.get_mut().unwrap();
+        let mut pg = vec![(&mut self.w, &mut grad.w),
+                          (&mut self.b, &mut grad.b)];
+        pg.extend(self.activation.params_mut().into_iter().zip(&mut grad.act));
+        pg
+    }
+
+    fn zero_grad(&mut self)
+    {
+        let grad = self.grad.get_mut().unwrap();
+        grad.w.map_inplace(|_| 0.0);
+        grad.b.map_inplace(|_| 0.0);
+        for g in &mut grad.act {
+            g.map_inplace(|_| 0.0);
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any
+    {
+        self
+    }
+}
+
+/*
+ * the biggest value under each k by k window of every channel. the windows
+ * never cover padding, and the gradient only goes back to the max.
+ */
+pub struct MaxPool2d
+{
+    win: Window,
+}
+
+impl MaxPool2d
+{
+    pub fn new(input: (usize, usize, usize), kernel: usize, stride: usize)
+               -> MaxPool2d
+    {
+        MaxPool2d { win: Window::new(input, kernel, stride, 0) }
+    }
+
+    pub fn out_shape(&self) -> (usize, usize, usize)
+    {
+        (self.win.c, self.win.oh, self.win.ow)
+    }
+
+    pub fn input_shape(&self) -> (usize, usize, usize)
+    {
+        self.win.input_shape()
+    }
+
+    pub fn kernel(&self) -> usize
+    {
+        self.win.k
+    }
+
+    pub fn stride(&self) -> usize
+    {
+        self.win.stride
+    }
+
+    // for every output row, the input row it took its max from, per sample.
+    fn argmax(&self, x: &Matrix<Number>) -> Vec<usize>
+    {
+        let (win, batch) = (&self.win, x.dim.1);
+        let mut arg = vec![0; win.c * win.out_len() * batch];
+        for ch in 0..win.c {
+            for oi in 0..win.oh {
+                for oj in 0..win.ow {
+                    let r = (ch * win.oh + oi) * win.ow + oj;
+                    for b in 0..batch {
+                        let mut best = win.at(ch, oi, oj, 0, 0).unwrap();
+                        for ki in 0..win.k {
+                            for kj in 0..win.k {
+                                let i = win.at(ch, oi, oj, ki, kj).unwrap();
+                                if x.a[i * batch + b] > x.a[best * batch + b] {
+                                    best = i;
+                                }
+                            }
+                        }
+                        arg[r * batch + b] = best;
+                    }
+                }
+            }
+        }
+        arg
+    }
+}
+
+impl Module for MaxPool2d
+{
+    fn name(&self) -> String
+    {
+        format!("maxpool {}x{}", self.win.k, self.win.k)
+    }
+
+    fn forward(&self, x: Matrix<Number>, cache: &mut Cache, _pass: &mut Pass)
+               -> Matrix<Number>
+    {
+        let batch = x.dim.1;
+        let arg = self.argmax(&x);
+        let out = Matrix::from_fn((self.win.c * self.win.out_len(), batch),
+                                  |r, b| x.a[arg[r * batch + b] * batch + b]);
+        cache.push(x);
+        out
+    }
+
+    fn backward(&self,
+                grad: Matrix<Number>,
+                cache: &mut Cache,
+                _pass: &mut Pass)
+                -> Matrix<Number>
+    {
+        let x = cache.pop();
+        let batch = x.dim.1;
+        let mut dx = Matrix::new_const(x.dim, 0.0);
+        for (k, i) in self.argmax(&x).into_iter().enumerate() {
+            dx.a[i * batch + k % batch] += grad.a[k];
+        }
+        dx
+    }
+
+    fn as_any(&self) -> &dyn Any
+    {
+        self
+    }
+}
+
+// the mean of each k by k window of every channel.
+pub struct AvgPool2d
+{
+    win: Window,
+}
+
+impl AvgPool2d
+{
+    pub fn new(input: (usize, usize, usize), kernel: usize, stride: usize)
+               -> AvgPool2d
+    {
+        AvgPool2d { win: Window::new(input, kernel, stride, 0) }
+    }
+
+    pub fn out_shape(&self) -> (usize, usize, usize)
+    {
+        (self.win.c, self.win.oh, self.win.ow)
+    }
+
+    pub fn input_shape(&self) -> (usize, usize, usize)
+    {
+        self.win.input_shape()
+    }
+
+    pub fn kernel(&self) -> usize
+    {
+        self.win.k
+    }
+
+    pub fn stride(&self) -> usize
+    {
+        self.win.stride
+    }
+}
+
+impl Module for AvgPool2d
+{
+    fn name(&self) -> String
+    {
+        format!("avgpool {}x{}", self.win.k, self.win.k)
+    }
+
+    fn forward(&self, x: Matrix<Number>, _cache: &mut Cache, _pass: &mut Pass)
+               -> Matrix<Number>
+    {
+        let (win, batch) = (&self.win, x.dim.1);
+        let scale = 1.0 / (win.k * win.k) as Number;
+        Matrix::from_fn((win.c * win.out_len(), batch), |r, b| {
+            let (ch, p) = (r / win.out_len(), r % win.out_len());
+            let (oi, oj) = (p / win.ow, p % win.ow);
+            let mut sum = 0.0;
+            for ki in 0..win.k {
+                for kj in 0..win.k {
+                    let i = win.at(ch, oi, oj, ki, kj).unwrap();
+                    sum += x.a[i * batch + b];
+                }
+            }
+            sum * scale
+        })
+    }
+
+    fn backward(&self,
+                grad: Matrix<Number>,
+                _cache: &mut Cache,
+                _pass: &mut Pass)
+                -> Matrix<Number>
+    {
+        let (win, batch) = (&self.win, grad.dim.1);
+        let scale = 1.0 / (win.k * win.k) as Number;
+        let mut dx = Matrix::new_const((win.in_len(), batch), 0.0);
+        for r in 0..grad.dim.0 {
+            let (ch, p) = (r / win.out_len(), r % win.out_len());
+            let (oi, oj) = (p / win.ow, p % win.ow);
+            for ki in 0..win.k {
+                for kj in 0..win.k {
+                    let i = win.at(ch, oi, oj, ki, kj).unwrap();
+                    for b in 0..batch {
+                        dx.a[i * batch + b] += grad.a[r * batch + b] * scale;
+                    }
+                }
+            }
+        }
+        dx
+    }
+
+    fn as_any(&self) -> &dyn Any
+    {
+        self
+    }
+}
+
+/*
+ * images are already flat columns, so this does nothing to the values. it
+ * marks where the image layers end and checks the size going into the
+ * dense ones.
+ */
+pub struct Flatten
+{
+    len: usize,
+}
+
+impl Flatten
+{
+    pub fn new(input: (usize, usize, usize)) -> Flatten
+    {
+        Flatten { len: input.0 * input.1 * input.2 }
+    }
+
+    pub fn features(&self) -> usize
+    {
+        self.len
+    }
+}
+
+impl Module for Flatten
+{
+    fn name(&self) -> String
+    {
+        format!("flatten {}", self.len)
+    }
+
+    fn forward(&self, x: Matrix<Number>, _cache: &mut Cache, _pass: &mut Pass)
+               -> Matrix<Number>
+    {
+        assert!(x.dim.0 == self.len,
+                "flatten expected {} rows, got {}",
+                self.len,
+                x.dim.0);
+        x
+    }
+
+    fn backward(&self,
+                grad: Matrix<Number>,
+                _cache: &mut Cache,
+                _pass: &mut Pass)
+                -> Matrix<Number>
+    {
+        grad
+    }
+
+    fn as_any(&self) -> &dyn Any
+    {
+        self
+    }
+}
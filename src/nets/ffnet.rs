@@ -1,10 +1,8 @@
 //use Matrix;
 use nets::*;
-use std;
 // use std::cmp::PartialOrd;
 // use std::ops::{Add, Div, Mul};
-use std::sync::{Arc, Mutex};
-use ::thread_pool::ThreadPool;
+use matrix::BLOCK_SHADES;
 
 
 extern crate crossbeam;
@@ -27,34 +25,293 @@ extern crate crossbeam;
 
 pub struct FFNet
 {
-    pub layers: Vec<Layer<AFunc>>,
-    l: usize,
+    pub layers: Sequential,
     test_set: Vec<(Matrix<Number>, Matrix<Number>)>,
-    grad_buf: Vec<Mutex<Layer<AFunc>>>,
     num_threads: usize,
-    output: OutFunc,
+    loss: Box<dyn Loss>,
+    clipping: Clipping,
+    preprocess: Preprocess,
+    checkpoint: Option<String>,
+    max_batches: Option<usize>,
+    optimizer: Box<dyn Optimizer>,
+    schedule: Box<dyn Schedule>,
+    sparse_input: bool,
+    batched: bool,
+    backend: Box<dyn Backend>,
+    seed: usize,
+    rng: rand::StdRng,
+    batches: usize,
+}
+
+/*
+ * caps on the batch averaged gradients before the optimizer sees them, so a
+ * bad batch can't throw the weights off. value clamps every entry to
+ * [-value, value]. norm then scales all gradients of the net together down
+ * to an L2 norm of norm, if it is over, which keeps their direction.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Clipping
+{
+    pub value: Option<Number>,
+    pub norm: Option<Number>,
+}
+
+/*
+ * what inputs are standardized with before they reach the first module,
+ * (x - mean) / std, in training and in eval alike. it is saved with the net
+ * so a loaded one sees its inputs the way it was trained on them.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Preprocess
+{
+    pub mean: Number,
+    pub std: Number,
+}
+
+impl Default for Preprocess
+{
+    fn default() -> Preprocess
+    {
+        Preprocess { mean: 0.0, std: 1.0 }
+    }
+}
+
+impl Preprocess
+{
+    // leaves inputs as they are.
+    pub fn none() -> Preprocess
+    {
+        Preprocess::default()
+    }
+
+    // the mean and std over every entry of every x.
+    pub fn fit(xs: &[Matrix<Number>]) -> Preprocess
+    {
+        let (mut sum, mut sq, mut n) = (0.0f64, 0.0f64, 0.0f64);
+        for e in xs.iter().flat_map(|x| &x.a) {
+            sum += *e as f64;
+            sq += *e as f64 * *e as f64;
+            n += 1.0;
+        }
+        if n == 0.0 {
+            return Preprocess::none();
+        }
+        let mean = sum / n;
+        let std = (sq / n - mean * mean).max(0.0).sqrt();
+        Preprocess { mean: mean as Number,
+                     std: if std > 0.0 { std as Number } else { 1.0 } }
+    }
+
+    pub fn apply(&self, x: Matrix<Number>) -> Matrix<Number>
+    {
+        if *self == Preprocess::none() {
+            return x;
+        }
+        let (mean, std) = (self.mean, self.std);
+        x.map(|e| (e - mean) / std)
+    }
+}
+
+impl Clipping
+{
+    pub fn none() -> Clipping
+    {
+        Clipping::default()
+    }
+
+    pub fn value(value: Number) -> Clipping
+    {
+        let clipping = Clipping { value: Some(value), norm: None };
+        clipping.check();
+        clipping
+    }
+
+    pub fn norm(norm: Number) -> Clipping
+    {
+        let clipping = Clipping { value: None, norm: Some(norm) };
+        clipping.check();
+        clipping
+    }
+
+    // a cap of 0 or less would zero or flip every gradient.
+    fn check(&self)
+    {
+        if let Some(v) = self.value {
+            assert!(v > 0.0, "the value cap has to be positive, not {}", v);
+        }
+        if let Some(n) = self.norm {
+            assert!(n > 0.0, "the norm cap has to be positive, not {}", n);
+        }
+    }
 }
 
 impl FFNet
 {
-    pub fn new(layers: Vec<Layer<AFunc>>,
+    // the optimizer keeps its state (velocities, moments, ...) per parameter
+    // of layers, so it should be a fresh one for every net.
+    pub fn new(layers: Sequential,
+               optimizer: Box<dyn Optimizer>,
                test_set: Vec<(Matrix<Number>, Matrix<Number>)>,
                num_threads: usize)
                -> FFNet
     {
-        let l = layers.len();
-        let mut grad_buf = Vec::with_capacity(l);
-        for layer in &layers {
-            grad_buf.push(Mutex::new(layer.clone_zeros()));
-        }
-        let output = OutFunc{};
+        let seed = ::rng::random_seed();
 
         FFNet { layers, 
-                l, 
                 test_set,
-                grad_buf,
                 num_threads,
-                output}
+                loss: Box::new(CrossEntropy::new()),
+                clipping: Clipping::none(),
+                preprocess: Preprocess::none(),
+                checkpoint: None,
+                max_batches: None,
+                optimizer,
+                schedule: Box::new(Constant),
+                sparse_input: false,
+                batched: false,
+                backend: Box::new(Reference),
+                seed,
+                rng: ::rng::seeded(seed),
+                batches: 0 }
+    }
+
+    // stacks each thread's share of a batch into one matrix, one column per
+    // sample, so every layer does a single matrix product per thread
+    // instead of one per sample.
+    pub fn set_batched(&mut self, on: bool)
+    {
+        self.batched = on;
+    }
+
+    // the kernels every layer runs on, Reference unless set otherwise.
+    pub fn set_backend(&mut self, backend: Box<dyn Backend>)
+    {
+        self.backend = backend;
+    }
+
+    pub fn backend(&self) -> &dyn Backend
+    {
+        &*self.backend
+    }
+
+    // what training minimizes, CrossEntropy unless set otherwise. eval
+    // gives back its prediction, e.g. the softmax for cross entropy.
+    pub fn set_loss(&mut self, loss: Box<dyn Loss>)
+    {
+        self.loss = loss;
+    }
+
+    pub fn loss(&self) -> &dyn Loss
+    {
+        &*self.loss
+    }
+
+    // no clipping unless set otherwise.
+    pub fn set_clipping(&mut self, clipping: Clipping)
+    {
+        clipping.check();
+        self.clipping = clipping;
+    }
+
+    pub fn clipping(&self) -> Clipping
+    {
+        self.clipping
+    }
+
+    // none unless set otherwise, see Preprocess::fit.
+    pub fn set_preprocess(&mut self, preprocess: Preprocess)
+    {
+        self.preprocess = preprocess;
+        self.warn_dense_input();
+    }
+
+    pub fn preprocess(&self) -> Preprocess
+    {
+        self.preprocess
+    }
+
+    // train saves the net here every time it tests it.
+    pub fn set_checkpoint(&mut self, path: &str)
+    {
+        self.checkpoint = Some(path.to_string());
+    }
+
+    // train returns once the net has seen this many batches in all, instead
+    // of going on until it's killed.
+    pub fn set_max_batches(&mut self, n: usize)
+    {
+        self.max_batches = Some(n);
+    }
+
+    // the modules, the loss and the preprocessing, in the format described
+    // in nets::save. the optimizer's state and the test set aren't saved.
+    pub fn save(&self, path: &str) -> Result<(), ModelError>
+    {
+        save::write_model(path, &self.layers, &*self.loss, self.preprocess)
+    }
+
+    // a net saved by save, with the rest given like to new.
+    pub fn load(path: &str,
+                optimizer: Box<dyn Optimizer>,
+                test_set: Vec<(Matrix<Number>, Matrix<Number>)>,
+                num_threads: usize)
+                -> Result<FFNet, ModelError>
+    {
+        let (layers, loss, preprocess) = save::read_model(path)?;
+        let mut net = FFNet::new(layers, optimizer, test_set, num_threads);
+        net.loss = loss;
+        net.preprocess = preprocess;
+        Ok(net)
+    }
+
+    // compares backward with numeric gradients of the loss, for every
+    // parameter of every module and every entry of x. see GradCheck.
+    pub fn check_gradients(&mut self,
+                           check: &GradCheck,
+                           x: &Matrix<Number>,
+                           y: &Matrix<Number>)
+                           -> Vec<Check>
+    {
+        let mut ms: Vec<&mut (dyn Module + 'static)> =
+            self.layers.modules_mut().iter_mut().map(|m| &mut **m).collect();
+        check.check_modules(&mut ms, &*self.loss, x, y)
+    }
+
+    // scales the step train was given as training goes on, Constant unless
+    // set otherwise.
+    pub fn set_schedule(&mut self, schedule: Box<dyn Schedule>)
+    {
+        self.schedule = schedule;
+    }
+
+    // reseeds the batch sampler and the per thread streams. with the same
+    // seed and the same initial layers, training replays exactly.
+    pub fn set_seed(&mut self, seed: usize)
+    {
+        self.seed = seed;
+        self.rng = ::rng::seeded(seed);
+        self.batches = 0;
+    }
+
+    pub fn seed(&self) -> usize
+    {
+        self.seed
+    }
+
+    // mnist inputs are mostly zeros, so the first layer can skip them.
+    // standardized ones aren't, so this does nothing with a Preprocess set.
+    pub fn set_sparse_input(&mut self, on: bool)
+    {
+        self.sparse_input = on;
+        self.warn_dense_input();
+    }
+
+    fn warn_dense_input(&self)
+    {
+        if self.sparse_input && self.preprocess != Preprocess::none() {
+            println!("preprocessing leaves no zeros in the inputs, so they \
+                      won't be taken as sparse");
+        }
     }
 
     pub fn train(&mut self,
@@ -62,137 +319,293 @@ impl FFNet
                 step: Number,
                 (x, y): (Vec<Matrix<Number>>, Vec<Matrix<Number>>))
     {
-        let mut i = 0;
-        loop {
+        // batchnorm takes its statistics from the columns of a batch, so
+        // one sample at a time would leave it a fixed affine map
+        let norms = self.layers
+                        .modules()
+                        .iter()
+                        .any(|m| m.as_any().is::<BatchNorm>());
+        assert!(!norms || (self.batched && batch_size > 1),
+                "a net with batchnorm needs set_batched(true) and batches of \
+                 at least 2 to train, not batched: {}, batch size: {}",
+                self.batched,
+                batch_size);
+        println!("training with seed {} on {} loss with {} on a {} schedule",
+                 self.seed,
+                 self.loss.name(),
+                 self.optimizer.name(),
+                 self.schedule.name());
+        let (mut loss_sum, mut norm_sum) = (0.0, 0.0);
+        while self.max_batches.is_none_or(|max| self.batches < max) {
             let mut batch = Vec::with_capacity(batch_size);
 
-            let mut rng = rand::thread_rng();
             for _ in 0..batch_size {
-                let rando = rng.gen::<usize>() % x.len();
+                let rando = self.rng.gen::<usize>() % x.len();
                 batch.push((&x[rando], &y[rando]));
             }
-            self.update_with_batch(batch);
+            loss_sum += self.update_with_batch(batch) / batch_size as Number;
+            norm_sum += self.clip_gradients(batch_size);
 
-            self.update_params(batch_size, step);
+            let rate = self.schedule.rate(self.batches, step);
+            self.update_params(batch_size, rate);
 
-            for layer in &mut self.grad_buf {
-                (*layer).lock().unwrap().zero_out();
-            }
+            self.zero_grad();
 
-            if (i + 1) % 1000 == 0 {
-            	println!("did batch {}", i + 1);
-                self.test();
+            self.batches += 1;
+            if self.batches.is_multiple_of(1000) {
+            	println!("did batch {}, learning rate {}, mean training loss {}",
+            	         self.batches,
+            	         rate,
+            	         loss_sum / 1000.0);
+            	println!("mean gradient norm before clipping {}",
+            	         norm_sum / 1000.0);
+            	loss_sum = 0.0;
+            	norm_sum = 0.0;
+                let test_loss = self.test();
+                self.schedule.observe(test_loss);
+                if let Some(ref path) = self.checkpoint {
+                    match self.save(path) {
+                        Ok(()) => println!("saved the net to {}", path),
+                        Err(e) => println!("couldn't save to {}: {}", path, e),
+                    }
+                }
             }
-            i = i + 1;
         }
 
     }
 
+    // adds the gradients of the whole batch, giving back its summed loss.
     pub fn update_with_batch(&self,
-                    mut batch: Vec<(&Matrix<Number>, &Matrix<Number>)>)
+                    batch: Vec<(&Matrix<Number>, &Matrix<Number>)>)
+                    -> Number
     {
         let batch_size = batch.len();
 
-        let props_per_thread = batch_size / self.num_threads;
-        let chunks = batch.chunks(props_per_thread);
+        // as even as it goes, and never a chunk of one when it can be
+        // helped: batched, that would be a batch without statistics.
+        let threads = self.num_threads.min(batch_size / 2).max(1);
+        let (size, extra) = (batch_size / threads, batch_size % threads);
+        let mut chunks = Vec::with_capacity(threads);
+        let mut rest = &batch[..];
+        for t in 0..threads {
+            let (chunk, tail) = rest.split_at(size + (t < extra) as usize);
+            chunks.push(chunk);
+            rest = tail;
+        }
 
         crossbeam::scope(|scope| {
-            for chunk in chunks {
+            let mut handles = Vec::new();
+            for (t, chunk) in chunks.into_iter().enumerate() {
                 let netref = self;
-                scope.spawn(move || {
+                let mut rng = ::rng::stream(self.seed, &[self.batches, t]);
+                handles.push(scope.spawn(move || {
+                    if netref.batched {
+                        let xs: Vec<_> = chunk.iter().map(|e| e.0).collect();
+                        let ys: Vec<_> = chunk.iter().map(|e| e.1).collect();
+                        return FFNet::add_to_gradient(netref,
+                                                      Matrix::hstack(&xs),
+                                                      Matrix::hstack(&ys),
+                                                      &mut rng);
+                    }
+                    let mut loss = 0.0;
                     for &(x, y) in chunk {
                         let (x, y) = (x.clone(), y.clone());
-                        FFNet::add_to_gradient(netref, x, y);
+                        loss += FFNet::add_to_gradient(netref, x, y, &mut rng);
                     }
-                });
+                    loss
+                }));
             }
-        });
+            handles.into_iter().map(|h| h.join()).sum()
+        })
     }
 
-    pub fn update_params(&mut self, batch_size: usize, step: Number)
+    // clips the gradients of the batch so far as set by set_clipping,
+    // giving back the global L2 norm of the batch averaged gradients from
+    // before.
+    pub fn clip_gradients(&mut self, batch_size: usize) -> Number
     {
-        for (i, layer) in self.layers.iter_mut().enumerate() {
-            layer.update(&*self.grad_buf[i].lock().unwrap() ,batch_size, step);
+        let scale = 1.0 / batch_size as Number;
+        let Clipping { value, norm: max_norm } = self.clipping;
+        let sq = |g: &Matrix<Number>| g.a.iter().map(|e| e * e).sum::<Number>();
+
+        // the gradients are sums over the batch, so the caps scale up too
+        let cap = value.map(|v| v * batch_size as Number);
+        let (mut norm_sq, mut clipped_sq) = (0.0, 0.0);
+        for m in self.layers.modules_mut() {
+            for (_, g) in m.params_and_grads() {
+                norm_sq += sq(g);
+                if let Some(cap) = cap {
+                    g.map_inplace(|e| e.max(-cap).min(cap));
+                    clipped_sq += sq(g);
+                }
+            }
+        }
+        if cap.is_none() {
+            clipped_sq = norm_sq;
         }
-    }
 
-    fn add_to_gradient(net: &FFNet,
-                        x: Matrix<Number>,
-                        y: Matrix<Number>)
-    {
-        let h = net.prop(x);
-        net.backprop(h, &y);
+        let norm = norm_sq.sqrt() * scale;
+        let clipped = clipped_sq.sqrt() * scale;
+        if let Some(max_norm) = max_norm {
+            if clipped > max_norm {
+                let shrink = max_norm / clipped;
+                for m in self.layers.modules_mut() {
+                    for (_, g) in m.params_and_grads() {
+                        g.map_inplace(|e| e * shrink);
+                    }
+                }
+            }
+        }
+        norm
     }
 
-    fn prop(&self, mut x: Matrix<Number>) -> Vec<Matrix<Number>>
+    // hands every parameter and its batch averaged gradient to the optimizer.
+    pub fn update_params(&mut self, batch_size: usize, step: Number)
     {
-        let mut h = Vec::with_capacity(self.l + 1);
+        let scale = 1.0 / batch_size as Number;
+        self.optimizer.begin_step();
 
-        h.push(x.clone());
-
-        for layer in &self.layers {
-            x = layer.prop(x);
-            h.push(x.clone());
+        let mut slot = 0;
+        for m in self.layers.modules_mut() {
+            for (p, g) in m.params_and_grads() {
+                self.optimizer.update(slot, p, &g.map(|e| e * scale), step);
+                slot += 1;
+            }
+            m.constrain(step);
         }
+    }
 
-        h
+    pub fn zero_grad(&mut self)
+    {
+        for m in self.layers.modules_mut() {
+            m.zero_grad();
+        }
     }
 
-    fn backprop(&self,
-                mut h: Vec<Matrix<Number>>,
-                y: &Matrix<Number>)
+    // x and y can be single samples or batches stacked as columns. gives
+    // back the loss summed over the samples.
+    fn add_to_gradient(net: &FFNet,
+                        x: Matrix<Number>,
+                        y: Matrix<Number>,
+                        rng: &mut rand::StdRng)
+                        -> Number
     {
-        let mut gradient = self.output.df(&self.output.f(h.pop().unwrap()), y);
+        let mut pass = Pass { train: true, be: net.backend(), rng };
+        let x = net.preprocess.apply(x);
 
-        for i in (0..self.l).rev() {
-            gradient = self.layers[i]
-                        .backprop(gradient, h.pop().unwrap(), &self.grad_buf[i]);
+        let sparse = net.preprocess == Preprocess::none();
+        if net.sparse_input && sparse && net.layers.sparse_ok() {
+            let x = SparseMatrix::from_dense(&x);
+            let (out, caches) = net.layers.forward_sparse(&x, &mut pass);
+            let (loss, gradient) = net.loss.loss(&out, &y);
+            net.layers.backward_sparse(gradient, caches, &mut pass);
+            loss
+        } else {
+            let (out, caches) = net.layers.forward(x, &mut pass);
+            let (loss, gradient) = net.loss.loss(&out, &y);
+            net.layers.backward(gradient, caches, &mut pass);
+            loss
         }
     }
+
     pub fn print_w_norms(&self)
     {
-	for (i, layer) in self.layers.iter().enumerate() {
-	    println!("w in layer: {} has norm: {}", i, layer.w.norm());
+	for (i, m) in self.layers.modules().iter().enumerate() {
+	    for p in m.parameters() {
+	        println!("{:?} in layer: {} ({}) has norm: {}",
+	                 p.dim, i, m.name(), p.norm());
+	    }
 	}
     }
-    pub fn test(&self)
+    // prints how the net does on the test set, giving back the test loss.
+    pub fn test(&self) -> Number
     {
-        fn argmax(x: &Matrix<Number>) -> usize
-        {
-            let mut gi = 0;
-            let mut max = x[0];
-            for (i, e) in x.a.iter().enumerate() {
-                if *e > max {
-                    gi = i;
-                    max = *e;
-                }
-            }
-            gi
-        }
-
 	self.print_w_norms();
 
-        let mut correct = 0.0;
-        for &(ref x, ref y) in &self.test_set {
-            let y_hat = self.eval(x);
-            if argmax(&y_hat) == argmax(y) {
-                correct = correct + 1.0;
-            }
-        }
-        println!("we are at {}% accuracy so far.",
-                 100.0 * correct / self.test_set.len() as f32);
+        println!("we are at {}% accuracy so far.", 100.0 * self.accuracy());
+        let loss = self.test_loss();
+        println!("mean test loss: {}", loss);
+        loss
     }
 
-    fn eval(&self, x: &Matrix<Number>) -> Matrix<Number>
+    // mean loss over the test set.
+    pub fn test_loss(&self) -> Number
     {
-        let mut x = x.clone();
+        let mut rng = self.eval_rng();
+        let total: Number = self.test_set
+                                .iter()
+                                .map(|(x, y)| {
+                                    self.loss.loss(&self.raw(x, &mut rng), y).0
+                                })
+                                .sum();
+        total / self.test_set.len() as Number
+    }
 
-        for layer in &self.layers {
-            x = layer.prop(x);
+    // fraction of the test set classified correctly.
+    pub fn accuracy(&self) -> f32
+    {
+        let mut rng = self.eval_rng();
+        let correct = self.test_set
+                          .iter()
+                          .filter(|&(x, y)| {
+                              argmax(&self.eval_with(x, &mut rng)) == argmax(y)
+                          })
+                          .count();
+        correct as f32 / self.test_set.len() as f32
+    }
+
+    pub fn test_set(&self) -> &[(Matrix<Number>, Matrix<Number>)]
+    {
+        &self.test_set
+    }
+
+    // test samples the net gets wrong, with its guess and the right label.
+    pub fn misclassified(&self) -> Vec<(&Matrix<Number>, usize, usize)>
+    {
+        let mut rng = self.eval_rng();
+        self.test_set
+            .iter()
+            .map(|(x, y)| (x, argmax(&self.eval_with(x, &mut rng)), argmax(y)))
+            .filter(|&(_, guess, label)| guess != label)
+            .collect()
+    }
+
+    // draws up to n of the misclassified test images, each width pixels
+    // wide, e.g. 28 for mnist.
+    pub fn print_misclassified(&self, n: usize, width: usize)
+    {
+        for (x, guess, label) in self.misclassified().into_iter().take(n) {
+            println!("guessed {} but it was a {}", guess, label);
+            print!("{}", x.render(width, BLOCK_SHADES));
         }
-	//println!("{:?}", x.clone());
+    }
 
-        self.output.f(x)
+    pub fn eval(&self, x: &Matrix<Number>) -> Matrix<Number>
+    {
+        self.eval_with(x, &mut self.eval_rng())
+    }
+
+    // nothing random happens outside training, so passes over the test set
+    // share one rng rather than seeding one per sample.
+    fn eval_rng(&self) -> rand::StdRng
+    {
+        ::rng::seeded(self.seed)
+    }
+
+    fn eval_with(&self, x: &Matrix<Number>, rng: &mut rand::StdRng)
+                 -> Matrix<Number>
+    {
+        self.loss.predict(self.raw(x, rng))
+    }
+
+    // the output of the last module, before the loss's prediction.
+    fn raw(&self, x: &Matrix<Number>, rng: &mut rand::StdRng)
+           -> Matrix<Number>
+    {
+        let mut pass = Pass { train: false, be: self.backend(), rng };
+        let x = self.preprocess.apply(x.clone());
+        let (x, _) = self.layers.forward(x, &mut pass);
+        x
     }
 }
 
@@ -0,0 +1,230 @@
+use nets::*;
+
+/*
+ * checks backward against central differences of the loss: every weight,
+ * bias and input gets nudged by h and 2h either way, with the net run
+ * forward again each time. the net itself runs in f32, so the nudges and the
+ * differences are worked out in f64 and divided by the steps that actually
+ * landed in f32, not by h.
+ *
+ * a plain central difference is off by about h^2 f''' / 6, too much for a
+ * tight tolerance at an h f32 can resolve. the differences over h and 2h are
+ * combined as
+ *     (4 d(h) - d(2h)) / 3
+ * (richardson extrapolation), which cancels that term and leaves an error
+ * of order h^4, about 1e-8 at the default h. what's left is rounding: the
+ * loss is only good to a few f32 ulps, so each numeric gradient carries
+ * about NOISE_ULPS * f32::EPSILON * |loss| / h of noise, and a check only
+ * fails on the error past that.
+ *
+ * every pass is a training pass with the same rng, so dropout masks repeat
+ * and batch norm uses batch statistics. the loss doesn't include any
+ * regularization, so check layers with Regularization::none(). all those
+ * passes go through forward, so every module is zero_grad'ed once the check
+ * is done, which also drops the batch statistics batch norm collected.
+ */
+pub struct GradCheck
+{
+    // big enough that f32 rounding in the loss doesn't swamp the
+    // difference, 1e-2 by default
+    pub h: f64,
+    // the largest relative error past the noise that still passes, 1e-3 by
+    // default
+    pub tolerance: f64,
+    // relative errors are taken against at least this much, so gradients
+    // that should be 0 are held to tolerance * floor absolutely, 1e-3 by
+    // default
+    pub floor: f64,
+}
+
+// how many f32 ulps of the loss a forward pass is taken to be off by.
+const NOISE_ULPS: f64 = 4.0;
+
+#[derive(Debug, Clone)]
+pub struct Check
+{
+    // e.g. "module 1 (dense 4x3) param 0 [5]" or "input [2]"
+    pub what: String,
+    pub numeric: f64,
+    pub analytic: f64,
+    // how far off numeric can be from f32 rounding alone
+    pub noise: f64,
+}
+
+impl Check
+{
+    // the error past the noise, relative to the gradient.
+    pub fn rel_err(&self, floor: f64) -> f64
+    {
+        let scale = self.numeric.abs().max(self.analytic.abs()).max(floor);
+        let err = (self.numeric - self.analytic).abs() - self.noise;
+        err.max(0.0) / scale
+    }
+}
+
+impl Default for GradCheck
+{
+    fn default() -> GradCheck
+    {
+        GradCheck { h: 1e-2, tolerance: 1e-3, floor: 1e-3 }
+    }
+}
+
+impl GradCheck
+{
+    pub fn new() -> GradCheck
+    {
+        GradCheck::default()
+    }
+
+    // a single module, e.g. a Layer, with loss on its output.
+    pub fn check_module(&self,
+                        m: &mut (dyn Module + 'static),
+                        loss: &dyn Loss,
+                        x: &Matrix<Number>,
+                        y: &Matrix<Number>)
+                        -> Vec<Check>
+    {
+        self.check_modules(&mut [m], loss, x, y)
+    }
+
+    // the checks that are off by more than the tolerance, worst first.
+    pub fn failures<'a>(&self, checks: &'a [Check]) -> Vec<&'a Check>
+    {
+        let mut bad: Vec<_> = checks.iter()
+                                    .filter(|c| {
+                                        c.rel_err(self.floor) > self.tolerance
+                                    })
+                                    .collect();
+        bad.sort_by(|a, b| {
+            b.rel_err(self.floor)
+             .partial_cmp(&a.rel_err(self.floor))
+             .unwrap()
+        });
+        bad
+    }
+
+    // modules run one after the other, with loss on the last one's output.
+    // FFNet::check_gradients does this for a whole net.
+    pub fn check_modules(&self,
+                         ms: &mut [&mut (dyn Module + 'static)],
+                         loss: &dyn Loss,
+                         x: &Matrix<Number>,
+                         y: &Matrix<Number>)
+                         -> Vec<Check>
+    {
+        let mut checks = Vec::new();
+
+        // analytic, from one backward pass
+        for m in ms.iter_mut() {
+            m.zero_grad();
+        }
+        let dx = backward(ms, loss, x, y);
+
+        for mi in 0..ms.len() {
+            let grads = ms[mi].gradients();
+            for (pi, g) in grads.iter().enumerate() {
+                for k in 0..g.len() {
+                    let old = ms[mi].parameters()[pi].a[k];
+                    let (numeric, noise) = self.numeric(old, |v| {
+                        ms[mi].params_and_grads()[pi].0.a[k] = v;
+                        loss_at(ms, loss, x, y)
+                    });
+                    ms[mi].params_and_grads()[pi].0.a[k] = old;
+                    checks.push(Check {
+                        what: format!("module {} ({}) param {} [{}]",
+                                      mi,
+                                      ms[mi].name(),
+                                      pi,
+                                      k),
+                        numeric,
+                        analytic: g.a[k] as f64,
+                        noise,
+                    });
+                }
+            }
+        }
+
+        let mut x = x.clone();
+        for k in 0..x.len() {
+            let old = x.a[k];
+            let (numeric, noise) = self.numeric(old, |v| {
+                x.a[k] = v;
+                loss_at(ms, loss, &x, y)
+            });
+            x.a[k] = old;
+
+            checks.push(Check { what: format!("input [{}]", k),
+                                numeric,
+                                analytic: dx.a[k] as f64,
+                                noise });
+        }
+
+        for m in ms.iter_mut() {
+            m.zero_grad();
+        }
+        checks
+    }
+
+    // the extrapolated central difference of the loss at around old, and
+    // its noise. loss_at sets the value to what it's given and runs the
+    // loss, the caller puts old back.
+    fn numeric<F>(&self, old: Number, mut loss_at: F) -> (f64, f64)
+        where F: FnMut(Number) -> f64
+    {
+        let mut biggest: f64 = 0.0;
+        let mut diff = |h: f64| {
+            let (up, down) = ((old as f64 + h) as Number,
+                              (old as f64 - h) as Number);
+            let (l_up, l_down) = (loss_at(up), loss_at(down));
+            biggest = biggest.max(l_up.abs()).max(l_down.abs());
+            (l_up - l_down) / (up as f64 - down as f64)
+        };
+        let (near, far) = (diff(self.h), diff(2.0 * self.h));
+
+        // near's noise weighs 4 / 3, far's, over twice the step, 1 / 6
+        let ulp = NOISE_ULPS * f32::EPSILON as f64 * biggest;
+        let noise = 1.5 * ulp / self.h;
+        ((4.0 * near - far) / 3.0, noise)
+    }
+}
+
+fn loss_at(ms: &[&mut (dyn Module + 'static)],
+           loss: &dyn Loss,
+           x: &Matrix<Number>,
+           y: &Matrix<Number>)
+           -> f64
+{
+    let mut rng = ::rng::seeded(0);
+    let mut pass = Pass { train: true, be: &Reference, rng: &mut rng };
+    let mut x = x.clone();
+    for m in ms {
+        x = m.forward(x, &mut Cache::new(), &mut pass);
+    }
+    loss.loss(&x, y).0 as f64
+}
+
+// runs forward and backward once, giving back the gradient wrt x.
+fn backward(ms: &[&mut (dyn Module + 'static)],
+            loss: &dyn Loss,
+            x: &Matrix<Number>,
+            y: &Matrix<Number>)
+            -> Matrix<Number>
+{
+    let mut rng = ::rng::seeded(0);
+    let mut pass = Pass { train: true, be: &Reference, rng: &mut rng };
+    let mut caches = Vec::with_capacity(ms.len());
+    let mut x = x.clone();
+    for m in ms {
+        let mut cache = Cache::new();
+        x = m.forward(x, &mut cache, &mut pass);
+        caches.push(cache);
+    }
+
+    let mut g = loss.loss(&x, y).1;
+    for m in ms.iter().rev() {
+        let mut cache = caches.pop().unwrap();
+        g = m.backward(g, &mut cache, &mut pass);
+    }
+    g
+}
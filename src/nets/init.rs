@@ -0,0 +1,122 @@
+use nets::*;
+
+/*
+ * how to fill a (fan_out, fan_in) weight matrix, or a bias. the scaled ones
+ * keep the variance of what flows through a layer about the same going in
+ * and out: Xavier for tanh and sigmoid like activations and He for relus.
+ * LeCun is He without the factor of 2 that makes up for relu zeroing half
+ * of its inputs. a bias has no fans, so those are only for weights.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Init
+{
+    // uniform in [-limit, limit)
+    Uniform(Number),
+    XavierUniform,
+    XavierNormal,
+    HeUniform,
+    HeNormal,
+    LeCunNormal,
+    // orthonormal rows (or columns, whichever there are fewer of), times a
+    // gain
+    Orthogonal(Number),
+    // normal with this std, redrawn past 2 std
+    TruncatedNormal(Number),
+    Constant(Number),
+}
+
+impl Init
+{
+    // a dense layer's weights, where the fans are just the dimensions.
+    pub fn matrix<R: Rng>(&self, dim: (usize, usize), rng: &mut R)
+                          -> Matrix<Number>
+    {
+        self.with_fans(dim, dim.1, dim.0, rng)
+    }
+
+    // weights that feed fan_out outputs from fan_in inputs each, which
+    // isn't their shape when they're shared, e.g. conv kernels.
+    pub fn with_fans<R: Rng>(&self,
+                             dim: (usize, usize),
+                             fan_in: usize,
+                             fan_out: usize,
+                             rng: &mut R)
+                             -> Matrix<Number>
+    {
+        let (fan_out, fan_in) = (fan_out as Number, fan_in as Number);
+        let uniform = |limit: Number, rng: &mut R| {
+            Matrix::new_rand(dim, -limit, limit, rng)
+        };
+        let normal = |std: Number, rng: &mut R| {
+            Matrix::new_normal(dim, 0.0, std, rng)
+        };
+
+        match *self {
+            Init::Uniform(limit) => uniform(limit, rng),
+            Init::XavierUniform => {
+                uniform((6.0 / (fan_in + fan_out)).sqrt(), rng)
+            }
+            Init::XavierNormal => {
+                normal((2.0 / (fan_in + fan_out)).sqrt(), rng)
+            }
+            Init::HeUniform => uniform((6.0 / fan_in).sqrt(), rng),
+            Init::HeNormal => normal((2.0 / fan_in).sqrt(), rng),
+            Init::LeCunNormal => normal((1.0 / fan_in).sqrt(), rng),
+            Init::Orthogonal(gain) => orthogonal(dim, gain, rng),
+            Init::TruncatedNormal(std) => {
+                Matrix::new_truncated_normal(dim, 0.0, std, rng)
+            }
+            Init::Constant(c) => Matrix::new_const(dim, c),
+        }
+    }
+
+    // an (n, 1) bias column.
+    pub fn bias<R: Rng>(&self, n: usize, rng: &mut R) -> Matrix<Number>
+    {
+        assert!(!self.scaled(), "{:?} is for weights, not biases", self);
+        self.matrix((n, 1), rng)
+    }
+
+    // whether this is scaled by the fans.
+    pub fn scaled(&self) -> bool
+    {
+        matches!(*self,
+                 Init::XavierUniform
+                 | Init::XavierNormal
+                 | Init::HeUniform
+                 | Init::HeNormal
+                 | Init::LeCunNormal)
+    }
+}
+
+// gram-schmidt on the rows of a normal matrix, transposed when there are
+// more rows than columns, since only that many can be orthogonal.
+fn orthogonal<R: Rng>(dim: (usize, usize), gain: Number, rng: &mut R)
+                      -> Matrix<Number>
+{
+    let (m, n) = dim;
+    let (rows, len) = if m <= n { (m, n) } else { (n, m) };
+    let mut q = Matrix::new_normal((rows, len), 0.0, 1.0, rng);
+
+    for i in 0..rows {
+        let (done, rest) = q.a.split_at_mut(i * len);
+        let row = &mut rest[..len];
+        for prev in done.chunks(len) {
+            let dot: Number = row.iter().zip(prev).map(|(a, b)| a * b).sum();
+            for (e, p) in row.iter_mut().zip(prev) {
+                *e -= dot * p;
+            }
+        }
+        let norm = row.iter().map(|e| e * e).sum::<Number>().sqrt();
+        for e in row.iter_mut() {
+            *e /= norm;
+        }
+    }
+
+    q.map_inplace(|e| e * gain);
+    if m <= n {
+        q
+    } else {
+        Matrix::from_fn(dim, |i, j| q.a[j * len + i])
+    }
+}
@@ -1,4 +1,5 @@
 use nets::*;
+use std::any::Any;
 use std::sync::Mutex;
 
 pub struct Layer<A: Activation<Number>>
@@ -6,97 +7,420 @@ pub struct Layer<A: Activation<Number>>
     pub w: Matrix<Number>,
     b: Matrix<Number>,
     activation: A,
+    reg: Regularization,
+    // the fraction of f(x) zeroed out during training
+    dropout: Number,
+    grad: Mutex<Grads>,
+}
+
+/*
+ * how a layer keeps its weights small. l2 and l1 are added to the gradient,
+ * once per sample, as the derivatives of l2 / 2 * |w|^2 and l1 * |w|_1.
+ * weight_decay and max_norm act on the weights directly after every update,
+ * so they don't go through the optimizer: w shrinks by lr * weight_decay * w
+ * and any row of w with an L2 norm above max_norm is scaled back onto it.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Regularization
+{
+    pub l2: Number,
+    pub l1: Number,
+    // whether l2, l1 and weight_decay also apply to the bias
+    pub decay_bias: bool,
+    pub weight_decay: Number,
+    pub max_norm: Option<Number>,
+}
+
+impl Regularization
+{
+    pub fn none() -> Regularization
+    {
+        Regularization::default()
+    }
+
+    pub fn l2(l2: Number) -> Regularization
+    {
+        Regularization { l2, ..Regularization::default() }
+    }
+
+    // adds the l2 and l1 terms for m, summed over samples samples.
+    fn penalize(&self,
+                m: &Matrix<Number>,
+                d: &mut Matrix<Number>,
+                samples: Number)
+    {
+        if self.l2 == 0.0 && self.l1 == 0.0 {
+            return;
+        }
+        for (e, w) in d.a.iter_mut().zip(&m.a) {
+            let sign = if *w == 0.0 { 0.0 } else { w.signum() };
+            *e += (self.l2 * w + self.l1 * sign) * samples;
+        }
+    }
+}
+
+// gradients summed over every sample since the last zero_grad.
+struct Grads
+{
+    w: Matrix<Number>,
+    b: Matrix<Number>,
+    // for the activation's own params, if it has any
+    act: Vec<Matrix<Number>>,
 }
 
 impl<A: Activation<Number>> Layer<A>
 {
-    pub fn new_rand(activation: A, into: usize, out: usize)
-                    -> Layer<A>
+    pub fn new_rand<R: Rng>(activation: A, into: usize, out: usize, rng: &mut R)
+                            -> Layer<A>
     {
 	let w_max = 1.0 /((into * out) as Number).sqrt();
-        Layer { w: Matrix::new_rand((out, into), -1.0 * w_max, w_max),
-                b: Matrix::new_const((out, 1), 0.01),
-                activation }
+        Layer::new_init(activation,
+                        into,
+                        out,
+                        Init::Uniform(w_max),
+                        Init::Constant(0.01),
+                        rng)
     }
 
-    pub fn clone_zeros(&self) -> Layer<A>
+    // like new_rand, with the weights and biases drawn from the given inits.
+    pub fn new_init<R: Rng>(activation: A,
+                            into: usize,
+                            out: usize,
+                            weights: Init,
+                            bias: Init,
+                            rng: &mut R)
+                            -> Layer<A>
     {
-        Layer {
-            w: Matrix::new_const(self.w.dim, 0.0),
-            b: Matrix::new_const(self.b.dim, 0.0),
-            activation: self.activation.clone(),
+        // the activation maps the input rows, so its params are one per
+        // input row or one for all of them.
+        for p in activation.params() {
+            assert!(p.dim.0 == 1 || p.dim.0 == into,
+                    "activation has {} params for {} inputs",
+                    p.dim.0,
+                    into);
         }
+        let act = activation.params()
+                            .iter()
+                            .map(|p| Matrix::new_const(p.dim, 0.0))
+                            .collect();
+        Layer { w: weights.matrix((out, into), rng),
+                b: bias.bias(out, rng),
+                activation,
+                reg: Regularization::l2(0.09),
+                dropout: 0.0,
+                grad: Mutex::new(Grads {
+                    w: Matrix::new_const((out, into), 0.0),
+                    b: Matrix::new_const((out, 1), 0.0),
+                    act,
+                }) }
+    }
+
+    pub fn b(&self) -> &Matrix<Number>
+    {
+        &self.b
+    }
+
+    pub fn activation(&self) -> &A
+    {
+        &self.activation
+    }
+
+    // l2 of 0.09 unless set otherwise.
+    pub fn set_regularization(&mut self, reg: Regularization)
+    {
+        self.reg = reg;
     }
 
-    pub fn update(&mut self, other: &Layer<A>, batch_size: usize, step: Number)
+    pub fn regularization(&self) -> &Regularization
     {
-        for (i, e) in &mut self.w.a.iter_mut().enumerate() {
-            *e = *e - (other.w.a[i] / batch_size as Number) * step;
+        &self.reg
+    }
+
+    /*
+     * inverted dropout on what goes into w, i.e. f(x): while training each
+     * element is zeroed with probability rate and the rest are scaled by
+     * 1 / (1 - rate), so nothing needs rescaling when evaluating, where
+     * dropout is off. on the first layer this drops inputs.
+     */
+    pub fn set_dropout(&mut self, rate: Number)
+    {
+        assert!((0.0..1.0).contains(&rate),
+                "dropout rate has to be in [0, 1), not {}",
+                rate);
+        self.dropout = rate;
+    }
+
+    pub fn dropout(&self) -> Number
+    {
+        self.dropout
+    }
+
+    fn dropout_mask<R: Rng>(&self, dim: (usize, usize), rng: &mut R)
+                            -> Matrix<Number>
+    {
+        let keep = 1.0 - self.dropout;
+        Matrix::from_fn(dim, |_, _| {
+            if rng.gen::<Number>() < keep { 1.0 / keep } else { 0.0 }
+        })
+    }
+
+    /*
+     * the parts of the regularization that act on the weights directly,
+     * meant for right after the optimizer updated them with rate lr.
+     */
+    pub fn constrain(&mut self, lr: Number)
+    {
+        if self.reg.weight_decay != 0.0 {
+            let shrink = 1.0 - lr * self.reg.weight_decay;
+            self.w.map_inplace(|e| e * shrink);
+            if self.reg.decay_bias {
+                self.b.map_inplace(|e| e * shrink);
+            }
         }
 
-        for (i, e) in self.b.a.iter_mut().enumerate() {
-            *e = *e - (other.b.a[i] / batch_size as Number) * step;
+        if let Some(max) = self.reg.max_norm {
+            let n = self.w.dim.1;
+            for row in self.w.a.chunks_mut(n.max(1)) {
+                let norm = row.iter().map(|e| e * e).sum::<Number>().sqrt();
+                if norm > max {
+                    for e in row {
+                        *e *= max / norm;
+                    }
+                }
+            }
         }
     }
 
-    pub fn zero_out(&mut self)
+    pub fn prop(&self, x: Matrix<Number>, be: &dyn Backend) -> Matrix<Number>
     {
-        for e in &mut self.w.a {
-            *e = 0.0;
-        }
+        self.prop_masked(x, None, be)
+    }
 
-        for e in &mut self.b.a {
-            *e = 0.0;
+    // prop with f(x) multiplied by a dropout mask.
+    fn prop_masked(&self,
+                   mut x: Matrix<Number>,
+                   mask: Option<&Matrix<Number>>,
+                   be: &dyn Backend)
+                   -> Matrix<Number>
+    {
+        be.map_rows(&mut x, &|i, e| self.activation.f_at(i, e));
+        if let Some(mask) = mask {
+            x = be.h_prod(&x, mask);
         }
+        let mut out = be.mul(&self.w, &x);
+        be.add_col_by(&mut out, &self.b);
+        out
+    }
+
+    // the sparse fast path only applies f to the stored entries, which is
+    // only right when f(0) == 0. it also loses the rows, which activations
+    // with params of their own may need, and has no dropout.
+    pub fn sparse_ok(&self) -> bool
+    {
+        self.activation.f(0.0) == 0.0
+            && self.activation.params().is_empty()
+            && self.dropout == 0.0
     }
 
-    pub fn prop(&self, mut x: Matrix<Number>) -> Matrix<Number>
+    pub fn prop_sparse(&self, x: &SparseMatrix<Number>, be: &dyn Backend)
+                       -> Matrix<Number>
     {
-        for e in x.a.iter_mut() {
-		*e = self.activation.f(*e);
-	}
-        let mut out = &self.w * &x;
-        out.add_by(&self.b);
+        let x = x.map_nonzero(|e| self.activation.f(e));
+        let mut out = self.w.mul_sparse(&x);
+        be.add_col_by(&mut out, &self.b);
         out
     }
 
+    fn regularize(&self,
+                  d_w: &mut Matrix<Number>,
+                  d_b: &mut Matrix<Number>,
+                  samples: Number)
+    {
+        self.reg.penalize(&self.w, d_w, samples);
+        if self.reg.decay_bias {
+            self.reg.penalize(&self.b, d_b, samples);
+        }
+    }
+
     /*
-     * TODO: Add regularization terms for layer parameters.
-     *	And do a so i can update the gradient_buf in multiple threads.
+     * same as backprop but for a sparse input. only meant for the first
+     * layer, so the gradient wrt the input is never computed.
+     * like backprop, the columns of x can be a whole batch.
+     */
+    pub fn backprop_sparse(&self,
+                           gradient: Matrix<Number>,
+                           x: &SparseMatrix<Number>,
+                           be: &dyn Backend)
+    {
+        let samples = x.dim.1 as Number;
+        let x = x.map_nonzero(|e| self.activation.f(e));
+        let mut d_w = gradient.mul_tr_sparse(&x);
+        let mut d_b = be.sum_cols(&gradient);
+        self.regularize(&mut d_w, &mut d_b, samples);
+
+        let mut grad = self.grad.lock().unwrap();
+
+        be.add_by(&mut grad.b, &d_b);
+        be.add_by(&mut grad.w, &d_w);
+    }
+
+    /*
+     * x can hold a whole batch as its columns, in which case the gradients
+     * for every sample are summed in at once.
      */
     pub fn backprop(&self,
                     gradient: Matrix<Number>,
-                    mut x: Matrix<Number>,
-                    gradient_buf: &Mutex<Layer<A>>)
+                    x: Matrix<Number>,
+                    be: &dyn Backend)
                     -> Matrix<Number>
     {
-	let alpha = 0.09;
-
+        self.backprop_masked(gradient, x, None, be)
+    }
 
-        let mut out_gradient = self.w.mul_tl(&gradient); //same as (w^t)*g
+    // backprop through prop_masked with the same mask.
+    fn backprop_masked(&self,
+                       gradient: Matrix<Number>,
+                       mut x: Matrix<Number>,
+                       mask: Option<&Matrix<Number>>,
+                       be: &dyn Backend)
+                       -> Matrix<Number>
+    {
+        let mut out_gradient = be.mul_tl(&self.w, &gradient); //same as (w^t)*g
+        if let Some(mask) = mask {
+            out_gradient = be.h_prod(&out_gradient, mask);
+        }
+        let d_act = self.activation.param_grads(&x, &out_gradient);
 
         //hadamar product of g with df with respect to the input.
-        for i in 0..out_gradient.len() {
-            out_gradient.a[i] = out_gradient[i] * self.activation.df(x[i]);
-        }
+        let mut df = x.clone();
+        be.map_rows(&mut df, &|i, e| self.activation.df_at(i, e));
+        let out_gradient = be.h_prod(&out_gradient, &df);
 
         //recompute f(x)
-        for e in x.a.iter_mut() {
-		*e = self.activation.f(*e);
-	}
+        be.map_rows(&mut x, &|i, e| self.activation.f_at(i, e));
+        if let Some(mask) = mask {
+            x = be.h_prod(&x, mask);
+        }
 
-        let mut d_w = gradient.mul_tr(&x);
+        let mut d_w = be.mul_tr(&gradient, &x);
+        let mut d_b = be.sum_cols(&gradient);
 
-	//regulate
-	for (i, e) in d_w.a.iter_mut().enumerate() {
-	    *e = *e + self.w[i] * alpha;
-	}
- 
-        let mut gradient_buf = gradient_buf.lock().unwrap();
+	//regulate, once per sample so batches match the per sample path
+        self.regularize(&mut d_w, &mut d_b, x.dim.1 as Number);
 
-        gradient_buf.b.add_by(&gradient);
-        gradient_buf.w.add_by(&d_w);
+        let mut grad = self.grad.lock().unwrap();
+
+        be.add_by(&mut grad.b, &d_b);
+        be.add_by(&mut grad.w, &d_w);
+        for (acc, d) in grad.act.iter_mut().zip(&d_act) {
+            be.add_by(acc, d);
+        }
 
         out_gradient
     }
 }
+
+impl<A> Module for Layer<A>
+    where A: Activation<Number> + Send + Sync + 'static
+{
+    fn name(&self) -> String
+    {
+        format!("dense {}x{}", self.w.dim.1, self.w.dim.0)
+    }
+
+    fn forward(&self, x: Matrix<Number>, cache: &mut Cache, pass: &mut Pass)
+               -> Matrix<Number>
+    {
+        cache.push(x.clone());
+        if !pass.train || self.dropout == 0.0 {
+            return self.prop(x, pass.be);
+        }
+
+        let mask = self.dropout_mask(x.dim, pass.rng);
+        let out = self.prop_masked(x, Some(&mask), pass.be);
+        cache.push(mask);
+        out
+    }
+
+    fn backward(&self,
+                grad: Matrix<Number>,
+                cache: &mut Cache,
+                pass: &mut Pass)
+                -> Matrix<Number>
+    {
+        if !pass.train || self.dropout == 0.0 {
+            return self.backprop(grad, cache.pop(), pass.be);
+        }
+
+        let mask = cache.pop();
+        self.backprop_masked(grad, cache.pop(), Some(&mask), pass.be)
+    }
+
+    fn parameters(&self) -> Vec<&Matrix<Number>>
+    {
+        let mut p = vec![&self.w, &self.b];
+        p.extend(self.activation.params());
+        p
+    }
+
+    fn gradients(&self) -> Vec<Matrix<Number>>
+    {
+        let grad = self.grad.lock().unwrap();
+        let mut g = vec![grad.w.clone(), grad.b.clone()];
+        g.extend(grad.act.iter().cloned());
+        g
+    }
+
+    fn params_and_grads(&mut self) -> Vec<(&mut Matrix<Number>,
+                                           &mut Matrix<Number>)>
+    {
+        let grad = self.grad.get_mut().unwrap();
+        let mut pg = vec![(&mut self.w, &mut grad.w),
+                          (&mut self.b, &mut grad.b)];
+        pg.extend(self.activation.params_mut().into_iter().zip(&mut grad.act));
+        pg
+    }
+
+    fn zero_grad(&mut self)
+    {
+        let grad = self.grad.get_mut().unwrap();
+        grad.w.map_inplace(|_| 0.0);
+        grad.b.map_inplace(|_| 0.0);
+        for g in &mut grad.act {
+            g.map_inplace(|_| 0.0);
+        }
+    }
+
+    fn constrain(&mut self, lr: Number)
+    {
+        Layer::constrain(self, lr);
+    }
+
+    fn as_any(&self) -> &dyn Any
+    {
+        self
+    }
+
+    fn sparse_ok(&self) -> bool
+    {
+        Layer::sparse_ok(self)
+    }
+
+    fn forward_sparse(&self,
+                      x: &SparseMatrix<Number>,
+                      cache: &mut Cache,
+                      pass: &mut Pass)
+                      -> Matrix<Number>
+    {
+        cache.save_sparse(x.clone());
+        self.prop_sparse(x, pass.be)
+    }
+
+    fn backward_sparse(&self,
+                       grad: Matrix<Number>,
+                       cache: &mut Cache,
+                       pass: &mut Pass)
+    {
+        self.backprop_sparse(grad, &cache.take_sparse(), pass.be);
+    }
+}
@@ -0,0 +1,262 @@
+use nets::*;
+use std::any::Any;
+
+/*
+ * what training minimizes. z is the raw output of the last module and y the
+ * target, one sample per column. loss gives back the loss summed over the
+ * samples along with its gradient wrt z, which is what gets backpropagated.
+ * anything that squashes z first, like the softmax in front of cross
+ * entropy, is part of the loss, and predict applies it on its own for eval.
+ */
+pub trait Loss: Send + Sync
+{
+    fn name(&self) -> &'static str;
+
+    fn loss(&self, z: &Matrix<Number>, y: &Matrix<Number>)
+            -> (Number, Matrix<Number>);
+
+    fn predict(&self, z: Matrix<Number>) -> Matrix<Number>
+    {
+        z
+    }
+
+    // for telling the losses apart when saving a net.
+    fn as_any(&self) -> &dyn Any;
+}
+
+fn check_dims(z: &Matrix<Number>, y: &Matrix<Number>)
+{
+    assert!(z.dim == y.dim,
+            "outputs {:?} and targets {:?} don't match",
+            z.dim,
+            y.dim);
+}
+
+// softmax over each column, then the cross entropy against y, which should
+// be a distribution (usually one hot). see Softmax::cross_entropy.
+#[derive(Default)]
+pub struct CrossEntropy
+{
+    pub softmax: Softmax,
+}
+
+impl CrossEntropy
+{
+    pub fn new() -> CrossEntropy
+    {
+        CrossEntropy::default()
+    }
+
+    pub fn with_temperature(temperature: Number) -> CrossEntropy
+    {
+        CrossEntropy { softmax: Softmax::with_temperature(temperature) }
+    }
+}
+
+impl Loss for CrossEntropy
+{
+    fn name(&self) -> &'static str
+    {
+        "cross_entropy"
+    }
+
+    fn as_any(&self) -> &dyn Any
+    {
+        self
+    }
+
+    fn loss(&self, z: &Matrix<Number>, y: &Matrix<Number>)
+            -> (Number, Matrix<Number>)
+    {
+        check_dims(z, y);
+        self.softmax.cross_entropy(z, y)
+    }
+
+    fn predict(&self, z: Matrix<Number>) -> Matrix<Number>
+    {
+        self.softmax.f(z)
+    }
+}
+
+// mean of (z - y)^2 over the outputs of each sample.
+pub struct MSE;
+
+impl Loss for MSE
+{
+    fn name(&self) -> &'static str
+    {
+        "mse"
+    }
+
+    fn as_any(&self) -> &dyn Any
+    {
+        self
+    }
+
+    fn loss(&self, z: &Matrix<Number>, y: &Matrix<Number>)
+            -> (Number, Matrix<Number>)
+    {
+        check_dims(z, y);
+        let m = z.dim.0 as Number;
+        let r = z - y;
+        let value = r.a.iter().map(|e| e * e).sum::<Number>() / m;
+        (value, r.map(|e| 2.0 * e / m))
+    }
+}
+
+// mean of |z - y| over the outputs of each sample.
+pub struct MAE;
+
+impl Loss for MAE
+{
+    fn name(&self) -> &'static str
+    {
+        "mae"
+    }
+
+    fn as_any(&self) -> &dyn Any
+    {
+        self
+    }
+
+    fn loss(&self, z: &Matrix<Number>, y: &Matrix<Number>)
+            -> (Number, Matrix<Number>)
+    {
+        check_dims(z, y);
+        let m = z.dim.0 as Number;
+        let r = z - y;
+        let value = r.a.iter().map(|e| e.abs()).sum::<Number>() / m;
+        let grad = r.map(|e| {
+            if e > 0.0 {
+                1.0 / m
+            } else if e < 0.0 {
+                -1.0 / m
+            } else {
+                0.0
+            }
+        });
+        (value, grad)
+    }
+}
+
+// squared error for residuals within delta, absolute error past it, so
+// outliers don't dominate. averaged over the outputs like MSE.
+pub struct Huber
+{
+    pub delta: Number,
+}
+
+impl Loss for Huber
+{
+    fn name(&self) -> &'static str
+    {
+        "huber"
+    }
+
+    fn as_any(&self) -> &dyn Any
+    {
+        self
+    }
+
+    fn loss(&self, z: &Matrix<Number>, y: &Matrix<Number>)
+            -> (Number, Matrix<Number>)
+    {
+        check_dims(z, y);
+        let (m, d) = (z.dim.0 as Number, self.delta);
+        let r = z - y;
+        let value = r.a
+                     .iter()
+                     .map(|e| {
+                         if e.abs() <= d {
+                             0.5 * e * e
+                         } else {
+                             d * (e.abs() - 0.5 * d)
+                         }
+                     })
+                     .sum::<Number>() / m;
+        (value, r.map(|e| e.clamp(-d, d) / m))
+    }
+}
+
+/*
+ * multiclass hinge loss against one hot targets: every wrong class whose
+ * score isn't at least 1 below the right one's adds the difference.
+ */
+pub struct Hinge;
+
+impl Loss for Hinge
+{
+    fn name(&self) -> &'static str
+    {
+        "hinge"
+    }
+
+    fn as_any(&self) -> &dyn Any
+    {
+        self
+    }
+
+    fn loss(&self, z: &Matrix<Number>, y: &Matrix<Number>)
+            -> (Number, Matrix<Number>)
+    {
+        check_dims(z, y);
+        let (m, n) = z.dim;
+        let mut grad = Matrix::new_const(z.dim, 0.0);
+        let mut value = 0.0;
+
+        for j in 0..n {
+            let c = argmax(&y.col(j));
+            let zc = z.a[c * n + j];
+            for i in (0..m).filter(|&i| i != c) {
+                let margin = 1.0 + z.a[i * n + j] - zc;
+                if margin > 0.0 {
+                    value += margin;
+                    grad.a[i * n + j] += 1.0;
+                    grad.a[c * n + j] -= 1.0;
+                }
+            }
+        }
+        (value, grad)
+    }
+}
+
+/*
+ * a sigmoid on every output, each its own yes/no against a y in [0, 1].
+ * computed from z directly, so big logits don't end up taking log(0).
+ */
+pub struct BinaryCrossEntropy;
+
+impl Loss for BinaryCrossEntropy
+{
+    fn name(&self) -> &'static str
+    {
+        "binary_cross_entropy"
+    }
+
+    fn as_any(&self) -> &dyn Any
+    {
+        self
+    }
+
+    fn loss(&self, z: &Matrix<Number>, y: &Matrix<Number>)
+            -> (Number, Matrix<Number>)
+    {
+        check_dims(z, y);
+        let m = z.dim.0 as Number;
+        // -y ln s(z) - (1 - y) ln(1 - s(z)), rearranged
+        let value = z.a
+                     .iter()
+                     .zip(&y.a)
+                     .map(|(z, y)| {
+                         z.max(0.0) - z * y + (-z.abs()).exp().ln_1p()
+                     })
+                     .sum::<Number>() / m;
+        let grad = z.zip_with(y, |z, y| (Sigmoid {}.f(z) - y) / m);
+        (value, grad)
+    }
+
+    fn predict(&self, z: Matrix<Number>) -> Matrix<Number>
+    {
+        z.map(|e| Sigmoid {}.f(e))
+    }
+}
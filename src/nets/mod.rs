@@ -1,23 +1,77 @@
-pub use self::ffnet::FFNet;
+pub use self::activation::*;
+pub mod activation;
+
+pub use self::backend::{Backend, Reference, Threaded};
+pub mod backend;
+
+pub use self::conv::{AvgPool2d, Conv2d, Flatten, MaxPool2d};
+pub mod conv;
+
+pub use self::ffnet::{Clipping, FFNet, Preprocess};
 pub mod ffnet;
 
-pub use self::layer::Layer;
+pub use self::gradcheck::{Check, GradCheck};
+pub mod gradcheck;
+
+pub use self::init::Init;
+pub mod init;
+
+pub use self::layer::{Layer, Regularization};
 pub mod layer;
 
+pub use self::loss::{BinaryCrossEntropy, CrossEntropy, Hinge, Huber, Loss, MAE,
+                     MSE};
+pub mod loss;
+
+pub use self::module::{Cache, Module, Pass, Sequential};
+pub mod module;
+
+pub use self::norm::{BatchNorm, LayerNorm};
+pub mod norm;
+
+pub mod op;
+
+pub use self::optim::{AdaGrad, Adam, AdamW, Momentum, Nesterov, Optimizer,
+                      RMSProp, Sgd};
+pub mod optim;
+
+pub use self::schedule::{Constant, CosineRestarts, Exponential, OneCycle,
+                         ReduceOnPlateau, Schedule, StepDecay, Warmup};
+pub mod schedule;
+
+pub use self::quant::{QuantReport, QuantizedNet, Unquantizable};
+pub mod quant;
+
+pub use self::save::ModelError;
+pub mod save;
+
 use Matrix;
+use SparseMatrix;
 
-use std::cmp::PartialEq;
 use std::f32;
 use std::ops::{Add, Mul, Sub};
 
 extern crate rand;
 use self::rand::Rng;
-use std::time::*;
 
 pub type Number = f32;
 
 pub type OutFunc = Softmax;
-pub type AFunc = RELU;
+pub type AFunc = Act;
+
+// index of the biggest entry, i.e. the predicted class.
+pub fn argmax(x: &Matrix<Number>) -> usize
+{
+    let mut gi = 0;
+    let mut max = x[0];
+    for (i, e) in x.a.iter().enumerate() {
+        if *e > max {
+            gi = i;
+            max = *e;
+        }
+    }
+    gi
+}
 
 
 pub trait Activation<T>
@@ -26,6 +80,38 @@ pub trait Activation<T>
     fn f(&self, x: T) -> T;
     fn df(&self, x: T) -> T;
     fn clone(&self) -> Self;
+
+    /*
+     * the rest is for activations with trainable parameters, like PReLU.
+     * row is the row of the layer input being mapped, so parameters can be
+     * per channel. the parameters get trained along with the layer's.
+     */
+    fn f_at(&self, _row: usize, x: T) -> T
+    {
+        self.f(x)
+    }
+
+    fn df_at(&self, _row: usize, x: T) -> T
+    {
+        self.df(x)
+    }
+
+    fn params(&self) -> Vec<&Matrix<T>>
+    {
+        Vec::new()
+    }
+
+    fn params_mut(&mut self) -> Vec<&mut Matrix<T>>
+    {
+        Vec::new()
+    }
+
+    // gradients of the params, given the input x and the gradient g wrt
+    // f(x), in the same order as params.
+    fn param_grads(&self, _x: &Matrix<T>, _g: &Matrix<T>) -> Vec<Matrix<T>>
+    {
+        Vec::new()
+    }
 }
 
 pub trait Output<T>
@@ -35,108 +121,88 @@ pub trait Output<T>
     fn df(&self, y_hat: &Matrix<T>, y: &Matrix<T>) -> Matrix<T>;
 }
 
-pub struct ATan {}
-
-impl Activation<f32> for ATan
+/*
+ * softmax over each column of logits z, p = exp(z / t) / sum exp(z / t).
+ * everything goes through log_softmax, which shifts each column by its max
+ * first, so big logits can't overflow exp. a temperature t above 1 flattens
+ * the distribution and below 1 sharpens it.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Softmax
 {
-    fn f(&self, x: f32) -> f32
-    {
-        f32::atan(x)
-    }
+    pub temperature: f32,
+}
 
-    fn df(&self, x: f32) -> f32
+impl Default for Softmax
+{
+    fn default() -> Softmax
     {
-        1.0 / (1.0 + x * x)
+        Softmax { temperature: 1.0 }
     }
+}
 
-    fn clone(&self) -> Self
+impl Softmax
+{
+    pub fn new() -> Softmax
     {
-        ATan{}
+        Softmax::default()
     }
-}
-
-pub struct RELU {}
 
-impl Activation<Number> for RELU
-{
-    fn f(&self, x: f32) -> f32
+    pub fn with_temperature(temperature: f32) -> Softmax
     {
-        if x > 0.0 { x } else { 0.0 }
+        assert!(temperature > 0.0, "temperature has to be positive");
+        Softmax { temperature }
     }
 
-    fn df(&self, x: f32) -> f32
+    // log of the softmax, z / t - log(sum exp(z / t)) per column.
+    pub fn log_softmax(&self, z: &Matrix<f32>) -> Matrix<f32>
     {
-        if x >= 0.0 { 1.0 } else { 0.0 }
+        let (m, n) = z.dim;
+        let t = self.temperature;
+        let mut maxes = vec![f32::NEG_INFINITY; n];
+        for (i, e) in z.a.iter().enumerate() {
+            maxes[i % n] = maxes[i % n].max(e / t);
+        }
+        // the shift comes off before the log of the sum, so a big max
+        // doesn't cost any precision.
+        let mut log_sums = vec![0.0; n];
+        for j in 0..n {
+            let sum: f32 = (0..m).map(|i| (z.a[i * n + j] / t - maxes[j]).exp())
+                                 .sum();
+            log_sums[j] = sum.ln();
+        }
+        Matrix::from_fn(z.dim, |i, j| {
+            (z.a[i * n + j] / t - maxes[j]) - log_sums[j]
+        })
     }
 
-    fn clone(&self) -> Self
+    /*
+     * softmax and cross entropy against y in one go, as the loss summed over
+     * the columns and its gradient wrt z. the loss comes straight from
+     * log_softmax, so a confident wrong guess gives a big loss instead of
+     * log(0).
+     */
+    pub fn cross_entropy(&self, z: &Matrix<f32>, y: &Matrix<f32>)
+                         -> (f32, Matrix<f32>)
     {
-        RELU{}
+        let log_p = self.log_softmax(z);
+        let loss = -log_p.a.iter().zip(&y.a).map(|(l, y)| y * l).sum::<f32>();
+        let p = log_p.map(f32::exp);
+        (loss, self.df(&p, y))
     }
 }
 
-
-pub struct Softmax{}
-
 impl Output<f32> for Softmax
 {
+    // each column is a separate sample.
     fn f(&self, x: Matrix<f32>) -> Matrix<f32>
     {
-        let mut y = Matrix::new(x.dim);
-
-        let mut exp_sum = 0.0;
-        for e in &x.a {
-            exp_sum = exp_sum + e.exp();
-        }
-
-        for e in x.a {
-            y.a.push(e.exp() / exp_sum);
-        }
-        y
+        self.log_softmax(&x).map(f32::exp)
     }
 
+    // the gradient of cross entropy wrt the logits.
     fn df(&self, y_hat: &Matrix<f32>, y: &Matrix<f32>) -> Matrix<f32>
     {
-        y_hat - y
+        (y_hat - y).map(|e| e / self.temperature)
     }
 }
-// pub struct MeanSquareError {}
-
-// impl<T> Cost<T> for MeanSquareError
-//     where T: Mul<Output = T> + Add<Output = T> + Sub<Output = T> + Copy
-// {
-//     type Domain = Matrix<T>;
-
-//     fn f(&self, y_hat: &Matrix<T>, y: &Matrix<T>) -> Matrix<T>
-//     {
-//         let (m, n) = y_hat.dim;
-//         let (m1, n1) = y.dim;
-//         if m1 != m || n1 != n || n != 1 {
-//             panic!("incompatable matrix dimenstions for cost func");
-//         }
-
-//         let v: Vec<_> = y_hat.a
-//                              .iter()
-//                              .zip(y.a.iter())
-//                              .map(|(y, yh)| (*y - *yh) * (*y - *yh))
-//                              .collect();
-
-//         Matrix::with_vec(y.dim, v)
-//     }
-
-//     fn df(&self, y_hat: &Matrix<T>, y: &Matrix<T>) -> Matrix<T>
-//     {
-//         let (m, n) = y_hat.dim;
-//         let (m1, n1) = y.dim;
-//         if m1 != m || n1 != n || n != 1 {
-//             panic!("incompatable matrix dimenstions for cost func");
-//         }
-
-//         let mut r = Matrix::new(y.dim);
-
-//         for i in 0..y.a.len() {
-//             r.a.push(y_hat[i] - y[i]);
-//         }
-//         r
-//     }
-// }
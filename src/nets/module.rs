@@ -0,0 +1,236 @@
+use nets::*;
+use std::any::Any;
+
+/*
+ * anything that can sit in a Sequential. a pass runs forward on every module
+ * and then backward in reverse, and the same module is shared by all the
+ * worker threads, so:
+ *  - forward saves what backward will need in the cache it is handed, never
+ *    in self.
+ *  - backward adds its parameter gradients to buffers kept behind a Mutex
+ *    inside the module, summed over every sample until zero_grad.
+ * x can be a single (n, 1) sample or a batch with one sample per column.
+ */
+pub trait Module: Send + Sync
+{
+    fn name(&self) -> String;
+
+    fn forward(&self, x: Matrix<Number>, cache: &mut Cache, pass: &mut Pass)
+               -> Matrix<Number>;
+
+    // takes the gradient wrt the output and gives back the one wrt the input.
+    fn backward(&self,
+                grad: Matrix<Number>,
+                cache: &mut Cache,
+                pass: &mut Pass)
+                -> Matrix<Number>;
+
+    // the defaults are for modules without parameters, like pooling.
+    fn parameters(&self) -> Vec<&Matrix<Number>>
+    {
+        Vec::new()
+    }
+
+    // copies of the accumulated gradients, in the same order as parameters.
+    fn gradients(&self) -> Vec<Matrix<Number>>
+    {
+        Vec::new()
+    }
+
+    // each parameter along with its gradient, for updating them.
+    fn params_and_grads(&mut self) -> Vec<(&mut Matrix<Number>,
+                                           &mut Matrix<Number>)>
+    {
+        Vec::new()
+    }
+
+    fn zero_grad(&mut self) {}
+
+    // called after every update with the rate it used, for anything that
+    // acts on the parameters directly, like decoupled weight decay.
+    fn constrain(&mut self, _lr: Number) {}
+
+    // state that isn't trained but is part of the model, like running
+    // statistics, to be saved along with the parameters.
+    fn buffers(&self) -> Vec<Matrix<Number>>
+    {
+        Vec::new()
+    }
+
+    fn load_buffers(&mut self, _buffers: Vec<Matrix<Number>>) {}
+
+    fn as_any(&self) -> &dyn Any;
+
+    // the sparse input fast path, see FFNet::set_sparse_input. modules that
+    // can't take a sparse input just densify it.
+    fn sparse_ok(&self) -> bool
+    {
+        false
+    }
+
+    fn forward_sparse(&self,
+                      x: &SparseMatrix<Number>,
+                      cache: &mut Cache,
+                      pass: &mut Pass)
+                      -> Matrix<Number>
+    {
+        self.forward(x.to_dense(), cache, pass)
+    }
+
+    // the gradient wrt a sparse input is never needed, since the input is
+    // the data.
+    fn backward_sparse(&self,
+                       grad: Matrix<Number>,
+                       cache: &mut Cache,
+                       pass: &mut Pass)
+    {
+        self.backward(grad, cache, pass);
+    }
+}
+
+// what one module saved during forward for one sample or batch.
+#[derive(Default)]
+pub struct Cache
+{
+    saved: Vec<Matrix<Number>>,
+    sparse: Option<SparseMatrix<Number>>,
+}
+
+impl Cache
+{
+    pub fn new() -> Cache
+    {
+        Cache::default()
+    }
+
+    pub fn push(&mut self, m: Matrix<Number>)
+    {
+        self.saved.push(m);
+    }
+
+    // things come back out in the reverse order they were pushed.
+    pub fn pop(&mut self) -> Matrix<Number>
+    {
+        self.saved.pop().expect("backward popped more than forward saved")
+    }
+
+    pub fn save_sparse(&mut self, m: SparseMatrix<Number>)
+    {
+        self.sparse = Some(m);
+    }
+
+    pub fn take_sparse(&mut self) -> SparseMatrix<Number>
+    {
+        self.sparse.take().expect("no sparse input was saved")
+    }
+}
+
+// settings for one pass through the net.
+pub struct Pass<'a>
+{
+    // false when evaluating, so e.g. dropout knows to do nothing
+    pub train: bool,
+    pub be: &'a dyn Backend,
+    // this thread's stream, for anything random in the forward pass
+    pub rng: &'a mut rand::StdRng,
+}
+
+pub struct Sequential
+{
+    modules: Vec<Box<dyn Module>>,
+}
+
+impl Sequential
+{
+    pub fn new(modules: Vec<Box<dyn Module>>) -> Sequential
+    {
+        Sequential { modules }
+    }
+
+    pub fn push(&mut self, m: Box<dyn Module>)
+    {
+        self.modules.push(m);
+    }
+
+    pub fn len(&self) -> usize
+    {
+        self.modules.len()
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.modules.is_empty()
+    }
+
+    pub fn modules(&self) -> &[Box<dyn Module>]
+    {
+        &self.modules
+    }
+
+    pub fn modules_mut(&mut self) -> &mut [Box<dyn Module>]
+    {
+        &mut self.modules
+    }
+
+    // the output of the last module, and a cache for each module to give
+    // back to backward.
+    pub fn forward(&self, mut x: Matrix<Number>, pass: &mut Pass)
+                   -> (Matrix<Number>, Vec<Cache>)
+    {
+        let mut caches = Vec::with_capacity(self.len());
+        for m in &self.modules {
+            let mut cache = Cache::new();
+            x = m.forward(x, &mut cache, pass);
+            caches.push(cache);
+        }
+        (x, caches)
+    }
+
+    pub fn backward(&self,
+                    mut grad: Matrix<Number>,
+                    mut caches: Vec<Cache>,
+                    pass: &mut Pass)
+                    -> Matrix<Number>
+    {
+        for m in self.modules.iter().rev() {
+            let mut cache = caches.pop().unwrap();
+            grad = m.backward(grad, &mut cache, pass);
+        }
+        grad
+    }
+
+    pub fn sparse_ok(&self) -> bool
+    {
+        self.modules.first().map(|m| m.sparse_ok()).unwrap_or(false)
+    }
+
+    // same as forward, but the first module takes a sparse input.
+    pub fn forward_sparse(&self, x: &SparseMatrix<Number>, pass: &mut Pass)
+                          -> (Matrix<Number>, Vec<Cache>)
+    {
+        let mut caches = Vec::with_capacity(self.len());
+        let mut cache = Cache::new();
+        let mut x = self.modules[0].forward_sparse(x, &mut cache, pass);
+        caches.push(cache);
+
+        for m in &self.modules[1..] {
+            let mut cache = Cache::new();
+            x = m.forward(x, &mut cache, pass);
+            caches.push(cache);
+        }
+        (x, caches)
+    }
+
+    pub fn backward_sparse(&self,
+                           mut grad: Matrix<Number>,
+                           mut caches: Vec<Cache>,
+                           pass: &mut Pass)
+    {
+        for m in self.modules[1..].iter().rev() {
+            let mut cache = caches.pop().unwrap();
+            grad = m.backward(grad, &mut cache, pass);
+        }
+        let mut cache = caches.pop().unwrap();
+        self.modules[0].backward_sparse(grad, &mut cache, pass);
+    }
+}
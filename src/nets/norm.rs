@@ -0,0 +1,392 @@
+use nets::*;
+use std::any::Any;
+use std::sync::Mutex;
+
+/*
+ * batch normalization: each row (feature) of x is shifted and scaled to
+ * mean 0 and variance 1 over the columns, then gets a learned scale gamma
+ * and shift beta. the statistics come from the batch while training, so it
+ * needs FFNet::set_batched, and from running averages of them otherwise.
+ * a batch of one sample has no statistics of its own, so it gets the running
+ * ones even while training, and doesn't update them. that would leave it a
+ * fixed affine map, so FFNet::train refuses to train it unbatched and never
+ * gives a thread a single sample.
+ */
+pub struct BatchNorm
+{
+    gamma: Matrix<Number>,
+    beta: Matrix<Number>,
+    // how much of the running statistics survive each update
+    pub momentum: Number,
+    pub eps: Number,
+    running_mean: Matrix<Number>,
+    running_var: Matrix<Number>,
+    grad: Mutex<NormGrads>,
+}
+
+struct NormGrads
+{
+    gamma: Matrix<Number>,
+    beta: Matrix<Number>,
+    // batch statistics seen since the last update, see constrain
+    mean_sum: Matrix<Number>,
+    var_sum: Matrix<Number>,
+    batches: usize,
+}
+
+impl NormGrads
+{
+    fn new(n: usize) -> NormGrads
+    {
+        NormGrads { gamma: Matrix::new_const((n, 1), 0.0),
+                    beta: Matrix::new_const((n, 1), 0.0),
+                    mean_sum: Matrix::new_const((n, 1), 0.0),
+                    var_sum: Matrix::new_const((n, 1), 0.0),
+                    batches: 0 }
+    }
+}
+
+impl BatchNorm
+{
+    pub fn new(features: usize) -> BatchNorm
+    {
+        BatchNorm { gamma: Matrix::new_const((features, 1), 1.0),
+                    beta: Matrix::new_const((features, 1), 0.0),
+                    momentum: 0.9,
+                    eps: 1e-5,
+                    running_mean: Matrix::new_const((features, 1), 0.0),
+                    running_var: Matrix::new_const((features, 1), 1.0),
+                    grad: Mutex::new(NormGrads::new(features)) }
+    }
+
+    pub fn features(&self) -> usize
+    {
+        self.gamma.dim.0
+    }
+
+    pub fn running_mean(&self) -> &Matrix<Number>
+    {
+        &self.running_mean
+    }
+
+    pub fn running_var(&self) -> &Matrix<Number>
+    {
+        &self.running_var
+    }
+
+    // gamma * (x - mean) / sqrt(var + eps) + beta, with the statistics as
+    // (n, 1) columns. also gives back the normalized x.
+    fn normalize(&self,
+                 x: &Matrix<Number>,
+                 mean: &Matrix<Number>,
+                 inv_std: &Matrix<Number>)
+                 -> (Matrix<Number>, Matrix<Number>)
+    {
+        let x_hat = Matrix::from_fn(x.dim, |i, j| {
+            (x.a[i * x.dim.1 + j] - mean.a[i]) * inv_std.a[i]
+        });
+        let y = Matrix::from_fn(x.dim, |i, j| {
+            self.gamma.a[i] * x_hat.a[i * x.dim.1 + j] + self.beta.a[i]
+        });
+        (x_hat, y)
+    }
+}
+
+// the mean and (biased) variance of each row of x.
+fn row_stats(x: &Matrix<Number>) -> (Matrix<Number>, Matrix<Number>)
+{
+    let n = x.dim.1 as Number;
+    let mean = x.sum_cols().map(|e| e / n);
+    let var = x.rows()
+               .zip(&mean.a)
+               .map(|(row, m)| {
+                   row.iter().map(|e| (e - m) * (e - m)).sum::<Number>() / n
+               })
+               .collect();
+    (mean, Matrix::with_vec((x.dim.0, 1), var))
+}
+
+impl Module for BatchNorm
+{
+    fn name(&self) -> String
+    {
+        format!("batchnorm {}", self.gamma.dim.0)
+    }
+
+    fn forward(&self, x: Matrix<Number>, cache: &mut Cache, pass: &mut Pass)
+               -> Matrix<Number>
+    {
+        if !pass.train || x.dim.1 == 1 {
+            let inv_std = self.running_var.map(|v| 1.0 / (v + self.eps).sqrt());
+            let (x_hat, y) = self.normalize(&x, &self.running_mean, &inv_std);
+            if pass.train {
+                cache.push(x_hat);
+                cache.push(inv_std);
+            }
+            return y;
+        }
+
+        let (mean, var) = row_stats(&x);
+        let inv_std = var.map(|v| 1.0 / (v + self.eps).sqrt());
+        let (x_hat, y) = self.normalize(&x, &mean, &inv_std);
+
+        {
+            let mut grad = self.grad.lock().unwrap();
+            grad.mean_sum.add_by(&mean);
+            // unbiased, since that is what the running variance estimates
+            let n = x.dim.1 as Number;
+            grad.var_sum.add_by(&var.map(|v| v * n / (n - 1.0)));
+            grad.batches += 1;
+        }
+
+        cache.push(x_hat);
+        cache.push(inv_std);
+        y
+    }
+
+    /*
+     * with x_hat the normalized x and dx_hat = g * gamma, each row gets
+     *     dx = inv_std / n * (n dx_hat - sum dx_hat - x_hat sum dx_hat x_hat)
+     * since the mean and variance depend on every sample in the batch. a
+     * single sample was normalized with the running stats, which are fixed,
+     * so there it's just dx_hat * inv_std.
+     */
+    fn backward(&self,
+                grad: Matrix<Number>,
+                cache: &mut Cache,
+                _pass: &mut Pass)
+                -> Matrix<Number>
+    {
+        let inv_std = cache.pop();
+        let x_hat = cache.pop();
+        let (m, n) = grad.dim;
+
+        let d_beta = grad.sum_cols();
+        let d_gamma = grad.h_prod(&x_hat).sum_cols();
+
+        let nf = n as Number;
+        let dx = Matrix::from_fn((m, n), |i, j| {
+            let k = i * n + j;
+            let dx_hat = grad.a[k] * self.gamma.a[i];
+            if n == 1 {
+                return dx_hat * inv_std.a[i];
+            }
+            let sum = d_beta.a[i] * self.gamma.a[i];
+            let dot = d_gamma.a[i] * self.gamma.a[i];
+            inv_std.a[i] / nf * (nf * dx_hat - sum - x_hat.a[k] * dot)
+        });
+
+        let mut acc = self.grad.lock().unwrap();
+        acc.gamma.add_by(&d_gamma);
+        acc.beta.add_by(&d_beta);
+        dx
+    }
+
+    fn parameters(&self) -> Vec<&Matrix<Number>>
+    {
+        vec![&self.gamma, &self.beta]
+    }
+
+    fn gradients(&self) -> Vec<Matrix<Number>>
+    {
+        let grad = self.grad.lock().unwrap();
+        vec![grad.gamma.clone(), grad.beta.clone()]
+    }
+
+    fn params_and_grads(&mut self) -> Vec<(&mut Matrix<Number>,
+                                           &mut Matrix<Number>)>
+    {
+        let grad = self.grad.get_mut().unwrap();
+        vec![(&mut self.gamma, &mut grad.gamma),
+             (&mut self.beta, &mut grad.beta)]
+    }
+
+    fn zero_grad(&mut self)
+    {
+        let grad = self.grad.get_mut().unwrap();
+        grad.gamma.map_inplace(|_| 0.0);
+        grad.beta.map_inplace(|_| 0.0);
+        // statistics that weren't folded in by constrain go too
+        grad.mean_sum.map_inplace(|_| 0.0);
+        grad.var_sum.map_inplace(|_| 0.0);
+        grad.batches = 0;
+    }
+
+    // the batch statistics of every thread get folded into the running ones
+    // here, once per update, so it doesn't matter what order threads ran in.
+    fn constrain(&mut self, _lr: Number)
+    {
+        let grad = self.grad.get_mut().unwrap();
+        if grad.batches == 0 {
+            return;
+        }
+
+        let (mo, count) = (self.momentum, grad.batches as Number);
+        for (r, s) in self.running_mean.a.iter_mut().zip(&grad.mean_sum.a) {
+            *r = mo * *r + (1.0 - mo) * s / count;
+        }
+        for (r, s) in self.running_var.a.iter_mut().zip(&grad.var_sum.a) {
+            *r = mo * *r + (1.0 - mo) * s / count;
+        }
+
+        grad.mean_sum.map_inplace(|_| 0.0);
+        grad.var_sum.map_inplace(|_| 0.0);
+        grad.batches = 0;
+    }
+
+    fn buffers(&self) -> Vec<Matrix<Number>>
+    {
+        vec![self.running_mean.clone(), self.running_var.clone()]
+    }
+
+    fn load_buffers(&mut self, mut buffers: Vec<Matrix<Number>>)
+    {
+        assert!(buffers.len() == 2, "batchnorm has 2 buffers");
+        self.running_var = buffers.pop().unwrap();
+        self.running_mean = buffers.pop().unwrap();
+    }
+
+    fn as_any(&self) -> &dyn Any
+    {
+        self
+    }
+}
+
+/*
+ * layer normalization: like BatchNorm, but each column (sample) is
+ * normalized over its own features, so it behaves the same for one sample
+ * as for a batch, in training and out of it. gain and bias are per feature.
+ */
+pub struct LayerNorm
+{
+    gain: Matrix<Number>,
+    bias: Matrix<Number>,
+    pub eps: Number,
+    grad: Mutex<GainGrads>,
+}
+
+struct GainGrads
+{
+    gain: Matrix<Number>,
+    bias: Matrix<Number>,
+}
+
+impl LayerNorm
+{
+    pub fn new(features: usize) -> LayerNorm
+    {
+        let zeros = Matrix::new_const((features, 1), 0.0);
+        LayerNorm { gain: Matrix::new_const((features, 1), 1.0),
+                    bias: zeros.clone(),
+                    eps: 1e-5,
+                    grad: Mutex::new(GainGrads { gain: zeros.clone(),
+                                                 bias: zeros }) }
+    }
+
+    pub fn features(&self) -> usize
+    {
+        self.gain.dim.0
+    }
+}
+
+impl Module for LayerNorm
+{
+    fn name(&self) -> String
+    {
+        format!("layernorm {}", self.gain.dim.0)
+    }
+
+    fn forward(&self, x: Matrix<Number>, cache: &mut Cache, _pass: &mut Pass)
+               -> Matrix<Number>
+    {
+        let (m, n) = x.dim;
+        let mf = m as Number;
+        let mut inv_std = Matrix::new_const((1, n), 0.0);
+        let mut x_hat = Matrix::new_const(x.dim, 0.0);
+
+        for j in 0..n {
+            let mean = (0..m).map(|i| x.a[i * n + j]).sum::<Number>() / mf;
+            let var = (0..m).map(|i| (x.a[i * n + j] - mean).powi(2))
+                            .sum::<Number>() / mf;
+            inv_std.a[j] = 1.0 / (var + self.eps).sqrt();
+            for i in 0..m {
+                x_hat.a[i * n + j] = (x.a[i * n + j] - mean) * inv_std.a[j];
+            }
+        }
+
+        let y = Matrix::from_fn(x.dim, |i, j| {
+            self.gain.a[i] * x_hat.a[i * n + j] + self.bias.a[i]
+        });
+        cache.push(x_hat);
+        cache.push(inv_std);
+        y
+    }
+
+    // same as BatchNorm's backward with rows and columns swapped, except
+    // that dx_hat = g * gain now changes along the sums.
+    fn backward(&self,
+                grad: Matrix<Number>,
+                cache: &mut Cache,
+                _pass: &mut Pass)
+                -> Matrix<Number>
+    {
+        let inv_std = cache.pop();
+        let x_hat = cache.pop();
+        let (m, n) = grad.dim;
+        let mf = m as Number;
+
+        let dx_hat = Matrix::from_fn(grad.dim, |i, j| {
+            grad.a[i * n + j] * self.gain.a[i]
+        });
+        let mut dx = Matrix::new_const(grad.dim, 0.0);
+        for j in 0..n {
+            let sum: Number = (0..m).map(|i| dx_hat.a[i * n + j]).sum();
+            let dot: Number = (0..m).map(|i| dx_hat.a[i * n + j]
+                                             * x_hat.a[i * n + j])
+                                    .sum();
+            for i in 0..m {
+                let k = i * n + j;
+                dx.a[k] = inv_std.a[j] / mf
+                          * (mf * dx_hat.a[k] - sum - x_hat.a[k] * dot);
+            }
+        }
+
+        let d_gain = grad.h_prod(&x_hat).sum_cols();
+        let d_bias = grad.sum_cols();
+        let mut acc = self.grad.lock().unwrap();
+        acc.gain.add_by(&d_gain);
+        acc.bias.add_by(&d_bias);
+        dx
+    }
+
+    fn parameters(&self) -> Vec<&Matrix<Number>>
+    {
+        vec![&self.gain, &self.bias]
+    }
+
+    fn gradients(&self) -> Vec<Matrix<Number>>
+    {
+        let grad = self.grad.lock().unwrap();
+        vec![grad.gain.clone(), grad.bias.clone()]
+    }
+
+    fn params_and_grads(&mut self) -> Vec<(&mut Matrix<Number>,
+                                           &mut Matrix<Number>)>
+    {
+        let grad = self.grad.get_mut().unwrap();
+        vec![(&mut self.gain, &mut grad.gain),
+             (&mut self.bias, &mut grad.bias)]
+    }
+
+    fn zero_grad(&mut self)
+    {
+        let grad = self.grad.get_mut().unwrap();
+        grad.gain.map_inplace(|_| 0.0);
+        grad.bias.map_inplace(|_| 0.0);
+    }
+
+    fn as_any(&self) -> &dyn Any
+    {
+        self
+    }
+}
@@ -0,0 +1,225 @@
+use nets::*;
+
+/*
+ * a small reverse mode autodiff tape. every op evaluates eagerly, pushes its
+ * result onto the tape and remembers what it needs to send gradients back to
+ * its inputs, so backward() is just a walk over the tape in reverse.
+ *
+ *     let mut t = Tape::new();
+ *     let (w, x) = (t.var(w), t.var(x));
+ *     let z = t.matmul(w, x);
+ *     let loss = t.softmax_cross_entropy(z, &y);
+ *     let grads = t.backward(loss);
+ *     grads.get(w) // d loss / d w
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Var(usize);
+
+enum Op
+{
+    Leaf,
+    MatMul(Var, Var),
+    // b may be a column, broadcast across the columns of a
+    Add(Var, Var),
+    Sub(Var, Var),
+    Hadamard(Var, Var),
+    // elementwise f, keeping f'(x) for the backward pass
+    Map(Var, Matrix<Number>),
+    // keeps the gradient of the summed loss wrt the logits
+    SoftmaxCrossEntropy(Var, Matrix<Number>),
+    Reshape(Var),
+    Sum(Var),
+}
+
+struct Node
+{
+    value: Matrix<Number>,
+    op: Op,
+}
+
+pub struct Tape
+{
+    nodes: Vec<Node>,
+}
+
+pub struct Gradients
+{
+    grads: Vec<Option<Matrix<Number>>>,
+}
+
+impl Gradients
+{
+    // None if v doesn't feed into whatever backward() was called on.
+    pub fn get(&self, v: Var) -> Option<&Matrix<Number>>
+    {
+        self.grads[v.0].as_ref()
+    }
+}
+
+impl Default for Tape
+{
+    fn default() -> Tape
+    {
+        Tape::new()
+    }
+}
+
+impl Tape
+{
+    pub fn new() -> Tape
+    {
+        Tape { nodes: Vec::new() }
+    }
+
+    fn push(&mut self, value: Matrix<Number>, op: Op) -> Var
+    {
+        self.nodes.push(Node { value, op });
+        Var(self.nodes.len() - 1)
+    }
+
+    // an input or parameter to differentiate with respect to.
+    pub fn var(&mut self, m: Matrix<Number>) -> Var
+    {
+        self.push(m, Op::Leaf)
+    }
+
+    pub fn value(&self, v: Var) -> &Matrix<Number>
+    {
+        &self.nodes[v.0].value
+    }
+
+    pub fn matmul(&mut self, a: Var, b: Var) -> Var
+    {
+        let value = self.value(a) * self.value(b);
+        self.push(value, Op::MatMul(a, b))
+    }
+
+    // a + b, where b is either the same shape as a or an (m, 1) column that
+    // gets added to every column of a, like a bias.
+    pub fn add(&mut self, a: Var, b: Var) -> Var
+    {
+        let mut value = self.value(a).clone();
+        if self.value(b).dim == value.dim {
+            value.add_by(self.value(b));
+        } else {
+            value.add_col_by(self.value(b));
+        }
+        self.push(value, Op::Add(a, b))
+    }
+
+    pub fn sub(&mut self, a: Var, b: Var) -> Var
+    {
+        let value = self.value(a) - self.value(b);
+        self.push(value, Op::Sub(a, b))
+    }
+
+    pub fn hadamard(&mut self, a: Var, b: Var) -> Var
+    {
+        let value = self.value(a).h_prod(self.value(b));
+        self.push(value, Op::Hadamard(a, b))
+    }
+
+    pub fn activation<A: Activation<Number>>(&mut self, a: Var, act: &A)
+                                             -> Var
+    {
+        let value = self.value(a).map(|e| act.f(e));
+        let df = self.value(a).map(|e| act.df(e));
+        self.push(value, Op::Map(a, df))
+    }
+
+    // mean cross entropy of softmax(logits) against y over the columns, as
+    // a (1, 1) matrix.
+    pub fn softmax_cross_entropy(&mut self, logits: Var, y: &Matrix<Number>)
+                                 -> Var
+    {
+        let z = self.value(logits);
+        assert!(z.dim == y.dim,
+                "logits {:?} and targets {:?} don't match",
+                z.dim,
+                y.dim);
+
+        let n = z.dim.1 as Number;
+        let (loss, d) = Softmax::new().cross_entropy(z, y);
+        let value = Matrix::with_vec((1, 1), vec![loss / n]);
+        self.push(value, Op::SoftmaxCrossEntropy(logits, d))
+    }
+
+    pub fn reshape(&mut self, a: Var, dim: (usize, usize)) -> Var
+    {
+        let value = self.value(a).reshape(dim);
+        self.push(value, Op::Reshape(a))
+    }
+
+    pub fn sum(&mut self, a: Var) -> Var
+    {
+        let s = self.value(a).a.iter().sum();
+        self.push(Matrix::with_vec((1, 1), vec![s]), Op::Sum(a))
+    }
+
+    // gradients of every var on the tape with respect to out, seeding
+    // d out / d out with ones.
+    pub fn backward(&self, out: Var) -> Gradients
+    {
+        let mut grads: Vec<Option<Matrix<Number>>> =
+            (0..self.nodes.len()).map(|_| None).collect();
+        grads[out.0] = Some(Matrix::new_const(self.value(out).dim, 1.0));
+
+        for i in (0..=out.0).rev() {
+            let g = match grads[i].take() {
+                Some(g) => g,
+                None => continue,
+            };
+
+            match self.nodes[i].op {
+                Op::Leaf => {}
+                Op::MatMul(a, b) => {
+                    accumulate(&mut grads, a, g.mul_tr(self.value(b)));
+                    accumulate(&mut grads, b, self.value(a).mul_tl(&g));
+                }
+                Op::Add(a, b) => {
+                    let gb = if self.value(b).dim == g.dim {
+                        g.clone()
+                    } else {
+                        g.sum_cols()
+                    };
+                    accumulate(&mut grads, a, g.clone());
+                    accumulate(&mut grads, b, gb);
+                }
+                Op::Sub(a, b) => {
+                    accumulate(&mut grads, a, g.clone());
+                    accumulate(&mut grads, b, g.map(|e| -e));
+                }
+                Op::Hadamard(a, b) => {
+                    accumulate(&mut grads, a, g.h_prod(self.value(b)));
+                    accumulate(&mut grads, b, g.h_prod(self.value(a)));
+                }
+                Op::Map(a, ref df) => {
+                    accumulate(&mut grads, a, g.h_prod(df));
+                }
+                Op::SoftmaxCrossEntropy(a, ref d) => {
+                    let scale = g.a[0] / d.dim.1 as Number;
+                    accumulate(&mut grads, a, d * scale);
+                }
+                Op::Reshape(a) => {
+                    accumulate(&mut grads, a, g.reshape(self.value(a).dim));
+                }
+                Op::Sum(a) => {
+                    let ga = Matrix::new_const(self.value(a).dim, g.a[0]);
+                    accumulate(&mut grads, a, ga);
+                }
+            }
+            grads[i] = Some(g);
+        }
+
+        Gradients { grads }
+    }
+}
+
+fn accumulate(grads: &mut [Option<Matrix<Number>>], v: Var, g: Matrix<Number>)
+{
+    match grads[v.0] {
+        Some(ref mut acc) => acc.add_by(&g),
+        None => grads[v.0] = Some(g),
+    }
+}
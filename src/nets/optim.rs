@@ -0,0 +1,337 @@
+use nets::*;
+
+/*
+ * turns gradients into parameter updates. FFNet owns one and hands it every
+ * parameter of every module after each batch, always in the same order, so
+ * the slot a parameter comes in with is a stable key for whatever state the
+ * optimizer keeps on it (velocities, moments, ...). g is already averaged
+ * over the batch.
+ */
+pub trait Optimizer: Send + Sync
+{
+    fn name(&self) -> &'static str;
+
+    // called once per batch before any of the updates.
+    fn begin_step(&mut self) {}
+
+    fn update(&mut self,
+              slot: usize,
+              p: &mut Matrix<Number>,
+              g: &Matrix<Number>,
+              lr: Number);
+}
+
+// the state for slot, zeros the first time it's asked for.
+fn state(states: &mut Vec<Matrix<Number>>, slot: usize, dim: (usize, usize))
+         -> &mut Matrix<Number>
+{
+    while states.len() <= slot {
+        states.push(Matrix::new_const((0, 0), 0.0));
+    }
+    if states[slot].dim != dim {
+        states[slot] = Matrix::new_const(dim, 0.0);
+    }
+    &mut states[slot]
+}
+
+// p -= lr * g
+pub struct Sgd;
+
+impl Optimizer for Sgd
+{
+    fn name(&self) -> &'static str
+    {
+        "sgd"
+    }
+
+    fn update(&mut self,
+              _slot: usize,
+              p: &mut Matrix<Number>,
+              g: &Matrix<Number>,
+              lr: Number)
+    {
+        for (e, d) in p.a.iter_mut().zip(&g.a) {
+            *e -= lr * d;
+        }
+    }
+}
+
+// v = mu * v + g, p -= lr * v
+pub struct Momentum
+{
+    pub mu: Number,
+    v: Vec<Matrix<Number>>,
+}
+
+impl Momentum
+{
+    pub fn new(mu: Number) -> Momentum
+    {
+        Momentum { mu, v: Vec::new() }
+    }
+}
+
+impl Optimizer for Momentum
+{
+    fn name(&self) -> &'static str
+    {
+        "momentum"
+    }
+
+    fn update(&mut self,
+              slot: usize,
+              p: &mut Matrix<Number>,
+              g: &Matrix<Number>,
+              lr: Number)
+    {
+        let v = state(&mut self.v, slot, p.dim);
+        for ((e, v), d) in p.a.iter_mut().zip(&mut v.a).zip(&g.a) {
+            *v = self.mu * *v + d;
+            *e -= lr * *v;
+        }
+    }
+}
+
+// momentum, but the step looks ahead along the new velocity:
+// v = mu * v + g, p -= lr * (g + mu * v)
+pub struct Nesterov
+{
+    pub mu: Number,
+    v: Vec<Matrix<Number>>,
+}
+
+impl Nesterov
+{
+    pub fn new(mu: Number) -> Nesterov
+    {
+        Nesterov { mu, v: Vec::new() }
+    }
+}
+
+impl Optimizer for Nesterov
+{
+    fn name(&self) -> &'static str
+    {
+        "nesterov"
+    }
+
+    fn update(&mut self,
+              slot: usize,
+              p: &mut Matrix<Number>,
+              g: &Matrix<Number>,
+              lr: Number)
+    {
+        let v = state(&mut self.v, slot, p.dim);
+        for ((e, v), d) in p.a.iter_mut().zip(&mut v.a).zip(&g.a) {
+            *v = self.mu * *v + d;
+            *e -= lr * (d + self.mu * *v);
+        }
+    }
+}
+
+// each element's step shrinks with the sum of its squared gradients so far.
+pub struct AdaGrad
+{
+    pub eps: Number,
+    s: Vec<Matrix<Number>>,
+}
+
+impl AdaGrad
+{
+    pub fn new() -> AdaGrad
+    {
+        AdaGrad { eps: 1e-8, s: Vec::new() }
+    }
+}
+
+impl Default for AdaGrad
+{
+    fn default() -> AdaGrad
+    {
+        AdaGrad::new()
+    }
+}
+
+impl Optimizer for AdaGrad
+{
+    fn name(&self) -> &'static str
+    {
+        "adagrad"
+    }
+
+    fn update(&mut self,
+              slot: usize,
+              p: &mut Matrix<Number>,
+              g: &Matrix<Number>,
+              lr: Number)
+    {
+        let s = state(&mut self.s, slot, p.dim);
+        for ((e, s), d) in p.a.iter_mut().zip(&mut s.a).zip(&g.a) {
+            *s += d * d;
+            *e -= lr * d / (s.sqrt() + self.eps);
+        }
+    }
+}
+
+// like AdaGrad, but with a decaying average of the squared gradients so the
+// step doesn't go to 0.
+pub struct RMSProp
+{
+    pub decay: Number,
+    pub eps: Number,
+    s: Vec<Matrix<Number>>,
+}
+
+impl RMSProp
+{
+    pub fn new(decay: Number) -> RMSProp
+    {
+        RMSProp { decay, eps: 1e-8, s: Vec::new() }
+    }
+}
+
+impl Optimizer for RMSProp
+{
+    fn name(&self) -> &'static str
+    {
+        "rmsprop"
+    }
+
+    fn update(&mut self,
+              slot: usize,
+              p: &mut Matrix<Number>,
+              g: &Matrix<Number>,
+              lr: Number)
+    {
+        let s = state(&mut self.s, slot, p.dim);
+        for ((e, s), d) in p.a.iter_mut().zip(&mut s.a).zip(&g.a) {
+            *s = self.decay * *s + (1.0 - self.decay) * d * d;
+            *e -= lr * d / (s.sqrt() + self.eps);
+        }
+    }
+}
+
+// decaying averages of the gradient and its square, corrected for starting
+// at 0.
+pub struct Adam
+{
+    pub beta1: Number,
+    pub beta2: Number,
+    pub eps: Number,
+    t: i32,
+    m: Vec<Matrix<Number>>,
+    v: Vec<Matrix<Number>>,
+}
+
+impl Adam
+{
+    pub fn new() -> Adam
+    {
+        Adam::with_betas(0.9, 0.999)
+    }
+
+    pub fn with_betas(beta1: Number, beta2: Number) -> Adam
+    {
+        Adam { beta1,
+               beta2,
+               eps: 1e-8,
+               t: 0,
+               m: Vec::new(),
+               v: Vec::new() }
+    }
+}
+
+impl Default for Adam
+{
+    fn default() -> Adam
+    {
+        Adam::new()
+    }
+}
+
+impl Optimizer for Adam
+{
+    fn name(&self) -> &'static str
+    {
+        "adam"
+    }
+
+    fn begin_step(&mut self)
+    {
+        self.t += 1;
+    }
+
+    fn update(&mut self,
+              slot: usize,
+              p: &mut Matrix<Number>,
+              g: &Matrix<Number>,
+              lr: Number)
+    {
+        let (b1, b2) = (self.beta1, self.beta2);
+        let c1 = 1.0 - b1.powi(self.t.max(1));
+        let c2 = 1.0 - b2.powi(self.t.max(1));
+        state(&mut self.v, slot, p.dim);
+        let m = state(&mut self.m, slot, p.dim);
+        let v = &mut self.v[slot];
+
+        for (((e, m), v), d) in p.a
+                                 .iter_mut()
+                                 .zip(&mut m.a)
+                                 .zip(&mut v.a)
+                                 .zip(&g.a)
+        {
+            *m = b1 * *m + (1.0 - b1) * d;
+            *v = b2 * *v + (1.0 - b2) * d * d;
+            *e -= lr * (*m / c1) / ((*v / c2).sqrt() + self.eps);
+        }
+    }
+}
+
+/*
+ * Adam for decoupled weight decay. the decay isn't applied here, where it
+ * would hit every parameter, biases and activation params included, but by
+ * each layer on its own weights after the update; see
+ * Regularization::weight_decay, and set l2 to 0 so the two don't stack.
+ */
+pub struct AdamW
+{
+    pub adam: Adam,
+}
+
+impl AdamW
+{
+    pub fn new() -> AdamW
+    {
+        AdamW { adam: Adam::new() }
+    }
+}
+
+impl Default for AdamW
+{
+    fn default() -> AdamW
+    {
+        AdamW::new()
+    }
+}
+
+impl Optimizer for AdamW
+{
+    fn name(&self) -> &'static str
+    {
+        "adamw"
+    }
+
+    fn begin_step(&mut self)
+    {
+        self.adam.begin_step();
+    }
+
+    fn update(&mut self,
+              slot: usize,
+              p: &mut Matrix<Number>,
+              g: &Matrix<Number>,
+              lr: Number)
+    {
+        self.adam.update(slot, p, g, lr);
+    }
+}
@@ -0,0 +1,309 @@
+use nets::*;
+use std::error;
+use std::fmt;
+
+/*
+ * post training int8 quantization. a real value r is stored as
+ *     q = round(r / scale) + zero
+ * clamped to [-128, 127]. weights get a scale and zero point per output row,
+ * the inputs to each layer get one per layer, calibrated by running the float
+ * net over some training samples and watching their range.
+ */
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QParams
+{
+    pub scale: f32,
+    pub zero: i32,
+}
+
+impl QParams
+{
+    // params covering [lo, hi]. the range is stretched to include 0 so that
+    // 0 is exactly representable.
+    pub fn from_range(lo: f32, hi: f32) -> QParams
+    {
+        let (lo, hi) = (lo.min(0.0), hi.max(0.0));
+        let scale = if hi > lo { (hi - lo) / 255.0 } else { 1.0 };
+        let zero = (-128.0 - lo / scale).round() as i32;
+        QParams { scale, zero: zero.clamp(-128, 127) }
+    }
+
+    pub fn quantize(&self, r: f32) -> i8
+    {
+        let q = (r / self.scale).round() as i32 + self.zero;
+        q.clamp(-128, 127) as i8
+    }
+
+    pub fn dequantize(&self, q: i8) -> f32
+    {
+        (q as i32 - self.zero) as f32 * self.scale
+    }
+}
+
+// an int8 matrix with per row quantization params.
+#[derive(Debug, Clone)]
+pub struct QMatrix
+{
+    pub dim: (usize, usize),
+    pub q: Vec<i8>,
+    pub rows: Vec<QParams>,
+}
+
+impl QMatrix
+{
+    pub fn quantize_rows(m: &Matrix<Number>) -> QMatrix
+    {
+        let mut q = Vec::with_capacity(m.len());
+        let mut rows = Vec::with_capacity(m.dim.0);
+
+        for row in m.rows() {
+            let lo = row.iter().cloned().fold(f32::INFINITY, f32::min);
+            let hi = row.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+            let p = QParams::from_range(lo, hi);
+            q.extend(row.iter().map(|e| p.quantize(*e)));
+            rows.push(p);
+        }
+
+        QMatrix { dim: m.dim, q, rows }
+    }
+
+    pub fn dequantize(&self) -> Matrix<Number>
+    {
+        let n = self.dim.1;
+        Matrix::from_fn(self.dim, |i, j| {
+            self.rows[i].dequantize(self.q[i * n + j])
+        })
+    }
+}
+
+// plain int8 gemm of a (m, k) by b (k, n), accumulating in i32.
+pub fn gemm_i8(a: &[i8], b: &[i8], m: usize, k: usize, n: usize) -> Vec<i32>
+{
+    assert!(a.len() == m * k && b.len() == k * n,
+            "can't multiply {} and {} elements as ({}, {})*({}, {})",
+            a.len(),
+            b.len(),
+            m,
+            k,
+            k,
+            n);
+
+    let mut r = vec![0i32; m * n];
+    for i in 0..m {
+        for p in 0..k {
+            let aip = a[i * k + p] as i32;
+            for j in 0..n {
+                r[i * n + j] += aip * b[p * n + j] as i32;
+            }
+        }
+    }
+    r
+}
+
+pub struct QLayer<A: Activation<Number>>
+{
+    pub w: QMatrix,
+    pub b: Matrix<Number>,
+    pub input: QParams,
+    activation: A,
+    // the sum of each row of w.q, for the zero point correction in prop
+    w_sums: Vec<i32>,
+}
+
+impl<A: Activation<Number>> QLayer<A>
+{
+    pub fn new(layer: &Layer<A>, input: QParams) -> QLayer<A>
+    {
+        let w = QMatrix::quantize_rows(&layer.w);
+        let k = w.dim.1;
+        let w_sums = w.q
+                      .chunks(k.max(1))
+                      .map(|row| row.iter().map(|e| *e as i32).sum())
+                      .collect();
+        QLayer { w,
+                 b: layer.b().clone(),
+                 input,
+                 activation: layer.activation().clone(),
+                 w_sums }
+    }
+
+    // same as Layer::prop, but w * f(x) is done in integers.
+    pub fn prop(&self, x: &Matrix<Number>) -> Matrix<Number>
+    {
+        let (m, k) = self.w.dim;
+        let n = x.dim.1;
+        let xq: Vec<i8> = x.a
+                           .iter()
+                           .map(|e| self.input.quantize(self.activation.f(*e)))
+                           .collect();
+
+        let acc = gemm_i8(&self.w.q, &xq, m, k, n);
+
+        /*
+         * sum_p (w_ip - zw_i)(x_pj - zx)
+         *     = sum_p w_ip x_pj - zx sum_p w_ip - zw_i sum_p x_pj + k zw_i zx
+         * so the zero points only cost a row sum of w, done once in new,
+         * and a column sum of x.
+         */
+        let zx = self.input.zero;
+        let x_sums: Vec<i32> = (0..n)
+            .map(|j| (0..k).map(|p| xq[p * n + j] as i32).sum())
+            .collect();
+
+        Matrix::from_fn((m, n), |i, j| {
+            let row = &self.w.rows[i];
+            let total = acc[i * n + j] - zx * self.w_sums[i]
+                        - row.zero * x_sums[j]
+                        + k as i32 * row.zero * zx;
+            total as f32 * row.scale * self.input.scale + self.b.a[i]
+        })
+    }
+}
+
+// only dense layers quantize; this names the first module that doesn't.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Unquantizable
+{
+    pub index: usize,
+    pub module: String,
+}
+
+impl fmt::Display for Unquantizable
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f,
+               "can only quantize dense layers, not module {} ({})",
+               self.index,
+               self.module)
+    }
+}
+
+impl error::Error for Unquantizable {}
+
+pub struct QuantizedNet
+{
+    pub layers: Vec<QLayer<AFunc>>,
+    output: OutFunc,
+    preprocess: Preprocess,
+}
+
+impl QuantizedNet
+{
+    // calibration should be a few hundred training inputs, enough to see the
+    // range each layer's inputs take.
+    pub fn calibrate(net: &FFNet, calibration: &[Matrix<Number>])
+                     -> Result<QuantizedNet, Unquantizable>
+    {
+        assert!(!calibration.is_empty(), "need samples to calibrate with");
+
+        let mut dense: Vec<&Layer<AFunc>> = Vec::new();
+        for (index, m) in net.layers.modules().iter().enumerate() {
+            match m.as_any().downcast_ref::<Layer<AFunc>>() {
+                Some(layer) => dense.push(layer),
+                None => {
+                    return Err(Unquantizable { index, module: m.name() });
+                }
+            }
+        }
+
+        let l = dense.len();
+        let mut lo = vec![f32::INFINITY; l];
+        let mut hi = vec![f32::NEG_INFINITY; l];
+
+        for x in calibration {
+            let mut x = net.preprocess().apply(x.clone());
+            for (i, layer) in dense.iter().enumerate() {
+                for e in &x.a {
+                    let fe = layer.activation().f(*e);
+                    lo[i] = lo[i].min(fe);
+                    hi[i] = hi[i].max(fe);
+                }
+                x = layer.prop(x, net.backend());
+            }
+        }
+
+        let mut layers = Vec::with_capacity(l);
+        for (i, layer) in dense.iter().enumerate() {
+            layers.push(QLayer::new(layer, QParams::from_range(lo[i], hi[i])));
+        }
+
+        Ok(QuantizedNet { layers,
+                          output: OutFunc::new(),
+                          preprocess: net.preprocess() })
+    }
+
+    pub fn eval(&self, x: &Matrix<Number>) -> Matrix<Number>
+    {
+        let mut x = self.preprocess.apply(x.clone());
+        for layer in &self.layers {
+            x = layer.prop(&x);
+        }
+        self.output.f(x)
+    }
+
+    pub fn bytes(&self) -> usize
+    {
+        self.layers
+            .iter()
+            .map(|l| l.w.q.len() + 8 * l.w.rows.len() + 4 * l.b.len() + 8)
+            .sum()
+    }
+}
+
+pub struct QuantReport
+{
+    pub float_accuracy: f32,
+    pub quant_accuracy: f32,
+    // fraction of test samples where both nets predict the same class
+    pub agreement: f32,
+    pub float_bytes: usize,
+    pub quant_bytes: usize,
+}
+
+impl QuantReport
+{
+    // runs both nets over the float net's test set.
+    pub fn new(net: &FFNet, q: &QuantizedNet) -> QuantReport
+    {
+        let (mut float_ok, mut quant_ok, mut agree) = (0, 0, 0);
+        for (x, y) in net.test_set() {
+            let label = argmax(y);
+            let fguess = argmax(&net.eval(x));
+            let qguess = argmax(&q.eval(x));
+            float_ok += (fguess == label) as usize;
+            quant_ok += (qguess == label) as usize;
+            agree += (fguess == qguess) as usize;
+        }
+
+        let n = net.test_set().len() as f32;
+        let float_bytes = net.layers
+                             .modules()
+                             .iter()
+                             .flat_map(|m| m.parameters())
+                             .map(|p| 4 * p.len())
+                             .sum();
+
+        QuantReport { float_accuracy: float_ok as f32 / n,
+                      quant_accuracy: quant_ok as f32 / n,
+                      agreement: agree as f32 / n,
+                      float_bytes,
+                      quant_bytes: q.bytes() }
+    }
+}
+
+impl fmt::Display for QuantReport
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        writeln!(f, "float accuracy: {:.2}%", 100.0 * self.float_accuracy)?;
+        writeln!(f, "int8 accuracy:  {:.2}% ({:+.2})",
+                 100.0 * self.quant_accuracy,
+                 100.0 * (self.quant_accuracy - self.float_accuracy))?;
+        writeln!(f, "agreement:      {:.2}%", 100.0 * self.agreement)?;
+        writeln!(f, "size: {} bytes -> {} bytes",
+                 self.float_bytes,
+                 self.quant_bytes)
+    }
+}
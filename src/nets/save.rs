@@ -0,0 +1,678 @@
+use nets::*;
+
+use std::error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+
+/*
+ * the file FFNet::save writes. every number is little endian, whatever the
+ * machine, and f32s are stored as their bits.
+ *
+ *   magic      8 bytes, "MNISTNET"
+ *   version    u32, FORMAT_VERSION
+ *   length     u64, bytes in the body
+ *   body       length bytes
+ *   checksum   u32, crc-32 (the zip/png one) of the body
+ *
+ * the body:
+ *
+ *   loss       u8 id, f32 parameter: the temperature of cross_entropy, the
+ *              delta of huber, 0 for the rest
+ *   preprocess f32 mean, f32 std, see Preprocess
+ *   modules    u32 count, then per module
+ *     kind     u8 id
+ *     config   per kind, see write_module
+ *     params   u32 count, then that many matrices, as in Module::parameters
+ *     buffers  u32 count, then that many matrices, as in Module::buffers
+ *
+ * a matrix is u32 rows, u32 cols and then rows * cols f32s, row by row, and
+ * an activation is a u8 id and an f32 parameter (the slope of leaky_relu,
+ * the alpha of elu, 0 for the rest). prelu keeps its slopes as the last
+ * param of its module.
+ *
+ * the ids never change meaning. anything that changes the layout bumps
+ * FORMAT_VERSION, and load refuses versions it doesn't know.
+ */
+pub const MAGIC: &[u8; 8] = b"MNISTNET";
+pub const FORMAT_VERSION: u32 = 1;
+
+const DENSE: u8 = 1;
+const CONV2D: u8 = 2;
+const MAX_POOL2D: u8 = 3;
+const AVG_POOL2D: u8 = 4;
+const FLATTEN: u8 = 5;
+const BATCH_NORM: u8 = 6;
+const LAYER_NORM: u8 = 7;
+
+const PRELU: u8 = 9;
+
+#[derive(Debug)]
+pub enum ModelError
+{
+    Io(io::Error),
+    // the file doesn't start with MAGIC
+    NotAModel,
+    // written by a version of the format this one can't read
+    Version(u32),
+    // the body doesn't hash to the stored checksum
+    Checksum { stored: u32, computed: u32 },
+    // the file ends before its contents do
+    Truncated,
+    // saving something the format has no id for
+    Unsupported(String),
+    // the body doesn't make sense, e.g. an unknown id or a matrix of the
+    // wrong size
+    Malformed(String),
+}
+
+impl fmt::Display for ModelError
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        match *self {
+            ModelError::Io(ref e) => write!(f, "{}", e),
+            ModelError::NotAModel => write!(f, "not a saved net"),
+            ModelError::Version(v) => {
+                write!(f,
+                       "saved with format version {}, this reads version {}",
+                       v,
+                       FORMAT_VERSION)
+            }
+            ModelError::Checksum { stored, computed } => {
+                write!(f,
+                       "corrupted: checksum is {:08x}, body hashes to {:08x}",
+                       stored,
+                       computed)
+            }
+            ModelError::Truncated => write!(f, "the file is cut short"),
+            ModelError::Unsupported(ref what) => {
+                write!(f, "can't save {}", what)
+            }
+            ModelError::Malformed(ref what) => write!(f, "malformed: {}", what),
+        }
+    }
+}
+
+impl error::Error for ModelError {}
+
+impl From<io::Error> for ModelError
+{
+    fn from(e: io::Error) -> ModelError
+    {
+        ModelError::Io(e)
+    }
+}
+
+// writes to a file next to path and renames it over path, so a save that
+// gets killed halfway leaves the last good file alone.
+pub fn write_model(path: &str,
+                   layers: &Sequential,
+                   loss: &dyn Loss,
+                   preprocess: Preprocess)
+                   -> Result<(), ModelError>
+{
+    let mut body = Vec::new();
+    write_loss(&mut body, loss)?;
+    put_f32(&mut body, preprocess.mean);
+    put_f32(&mut body, preprocess.std);
+    put_u32(&mut body, layers.len() as u32);
+    for m in layers.modules() {
+        write_module(&mut body, &**m)?;
+    }
+
+    let mut out = Vec::with_capacity(body.len() + 24);
+    out.extend_from_slice(MAGIC);
+    put_u32(&mut out, FORMAT_VERSION);
+    out.extend_from_slice(&(body.len() as u64).to_le_bytes());
+    out.extend_from_slice(&body);
+    put_u32(&mut out, crc32(&body));
+
+    let tmp = format!("{}.tmp", path);
+    File::create(&tmp)?.write_all(&out)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+pub fn read_model(path: &str)
+                  -> Result<(Sequential, Box<dyn Loss>, Preprocess), ModelError>
+{
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+    let mut r = Reader { bytes: &bytes, pos: 0 };
+
+    if bytes.len() < MAGIC.len() || r.take(MAGIC.len())? != &MAGIC[..] {
+        return Err(ModelError::NotAModel);
+    }
+    let version = r.u32()?;
+    if version != FORMAT_VERSION {
+        return Err(ModelError::Version(version));
+    }
+    let len = r.u64()?;
+    if len > r.left() as u64 {
+        return Err(ModelError::Truncated);
+    }
+    let body = r.take(len as usize)?;
+    let stored = r.u32()?;
+    if r.left() > 0 {
+        return Err(ModelError::Malformed(format!("{} bytes past the end",
+                                                 r.left())));
+    }
+    let computed = crc32(body);
+    if stored != computed {
+        return Err(ModelError::Checksum { stored, computed });
+    }
+
+    let mut r = Reader { bytes: body, pos: 0 };
+    let loss = read_loss(&mut r)?;
+    let preprocess = Preprocess { mean: r.f32()?, std: r.f32()? };
+    let Preprocess { mean, std } = preprocess;
+    if !mean.is_finite() || !std.is_finite() || std <= 0.0 {
+        return Err(ModelError::Malformed(format!("preprocess mean {}, std {}",
+                                                 mean,
+                                                 std)));
+    }
+    let count = r.u32()?;
+    let mut layers = Sequential::new(Vec::new());
+    for i in 0..count {
+        match read_module(&mut r) {
+            Ok(m) => layers.push(m),
+            Err(ModelError::Malformed(what)) => {
+                let what = format!("module {}: {}", i, what);
+                return Err(ModelError::Malformed(what));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    if r.left() > 0 {
+        return Err(ModelError::Malformed(format!("{} bytes left in the body",
+                                                 r.left())));
+    }
+    Ok((layers, loss, preprocess))
+}
+
+fn write_loss(out: &mut Vec<u8>, loss: &dyn Loss) -> Result<(), ModelError>
+{
+    let any = loss.as_any();
+    let (id, param) = if let Some(ce) = any.downcast_ref::<CrossEntropy>() {
+        (0, ce.softmax.temperature)
+    } else if any.is::<MSE>() {
+        (1, 0.0)
+    } else if any.is::<MAE>() {
+        (2, 0.0)
+    } else if let Some(h) = any.downcast_ref::<Huber>() {
+        (3, h.delta)
+    } else if any.is::<Hinge>() {
+        (4, 0.0)
+    } else if any.is::<BinaryCrossEntropy>() {
+        (5, 0.0)
+    } else {
+        return Err(ModelError::Unsupported(format!("the {} loss",
+                                                   loss.name())));
+    };
+    out.push(id);
+    put_f32(out, param);
+    Ok(())
+}
+
+fn read_loss(r: &mut Reader) -> Result<Box<dyn Loss>, ModelError>
+{
+    let (id, param) = (r.u8()?, r.f32()?);
+    Ok(match id {
+        0 if param > 0.0 => Box::new(CrossEntropy::with_temperature(param)),
+        1 => Box::new(MSE),
+        2 => Box::new(MAE),
+        3 if param.is_finite() && param > 0.0 => {
+            Box::new(Huber { delta: param })
+        }
+        4 => Box::new(Hinge),
+        5 => Box::new(BinaryCrossEntropy),
+        _ => {
+            return Err(ModelError::Malformed(format!("loss id {} ({})",
+                                                     id,
+                                                     param)))
+        }
+    })
+}
+
+fn act_id(act: Act) -> (u8, Number)
+{
+    match act {
+        Act::ATan => (0, 0.0),
+        Act::Relu => (1, 0.0),
+        Act::Sigmoid => (2, 0.0),
+        Act::Tanh => (3, 0.0),
+        Act::LeakyRelu(slope) => (4, slope),
+        Act::Elu(alpha) => (5, alpha),
+        Act::Gelu => (6, 0.0),
+        Act::Softplus => (7, 0.0),
+        Act::Swish => (8, 0.0),
+    }
+}
+
+// Act, or None for PRELU, whose slopes come with the params.
+fn read_act(r: &mut Reader) -> Result<Option<Act>, ModelError>
+{
+    let (id, param) = (r.u8()?, r.f32()?);
+    Ok(Some(match id {
+        0 => Act::ATan,
+        1 => Act::Relu,
+        2 => Act::Sigmoid,
+        3 => Act::Tanh,
+        4 => Act::LeakyRelu(param),
+        5 => Act::Elu(param),
+        6 => Act::Gelu,
+        7 => Act::Softplus,
+        8 => Act::Swish,
+        PRELU => return Ok(None),
+        _ => return Err(ModelError::Malformed(format!("activation id {}", id))),
+    }))
+}
+
+/*
+ * the config of each kind, all u32s unless noted:
+ *   dense      in, out, activation, dropout f32, then the regularization:
+ *              l2 f32, l1 f32, decay_bias u8, weight_decay f32,
+ *              has max_norm u8, max_norm f32
+ *   conv2d     channels, height, width, out channels, kernel, stride,
+ *              padding, activation
+ *   pooling    channels, height, width, kernel, stride
+ *   flatten    length
+ *   batchnorm  features, momentum f32, eps f32
+ *   layernorm  features, eps f32
+ */
+fn write_module(out: &mut Vec<u8>, m: &dyn Module) -> Result<(), ModelError>
+{
+    let any = m.as_any();
+    if let Some(l) = any.downcast_ref::<Layer<Act>>() {
+        write_dense(out, l, act_id(*l.activation()));
+    } else if let Some(l) = any.downcast_ref::<Layer<PReLU>>() {
+        write_dense(out, l, (PRELU, 0.0));
+    } else if let Some(c) = any.downcast_ref::<Conv2d<Act>>() {
+        write_conv(out, c, act_id(*c.activation()));
+    } else if let Some(c) = any.downcast_ref::<Conv2d<PReLU>>() {
+        write_conv(out, c, (PRELU, 0.0));
+    } else if let Some(p) = any.downcast_ref::<MaxPool2d>() {
+        out.push(MAX_POOL2D);
+        put_pool(out, p.input_shape(), p.kernel(), p.stride());
+    } else if let Some(p) = any.downcast_ref::<AvgPool2d>() {
+        out.push(AVG_POOL2D);
+        put_pool(out, p.input_shape(), p.kernel(), p.stride());
+    } else if let Some(f) = any.downcast_ref::<Flatten>() {
+        out.push(FLATTEN);
+        put_usizes(out, &[f.features()]);
+    } else if let Some(bn) = any.downcast_ref::<BatchNorm>() {
+        out.push(BATCH_NORM);
+        put_usizes(out, &[bn.features()]);
+        put_f32(out, bn.momentum);
+        put_f32(out, bn.eps);
+    } else if let Some(ln) = any.downcast_ref::<LayerNorm>() {
+        out.push(LAYER_NORM);
+        put_usizes(out, &[ln.features()]);
+        put_f32(out, ln.eps);
+    } else {
+        return Err(ModelError::Unsupported(m.name()));
+    }
+
+    let params = m.parameters();
+    put_u32(out, params.len() as u32);
+    for p in params {
+        put_matrix(out, p);
+    }
+    let buffers = m.buffers();
+    put_u32(out, buffers.len() as u32);
+    for b in &buffers {
+        put_matrix(out, b);
+    }
+    Ok(())
+}
+
+fn write_dense<A>(out: &mut Vec<u8>, l: &Layer<A>, (id, param): (u8, Number))
+    where A: Activation<Number>
+{
+    out.push(DENSE);
+    put_usizes(out, &[l.w.dim.1, l.w.dim.0]);
+    out.push(id);
+    put_f32(out, param);
+    put_f32(out, l.dropout());
+
+    let reg = l.regularization();
+    put_f32(out, reg.l2);
+    put_f32(out, reg.l1);
+    out.push(reg.decay_bias as u8);
+    put_f32(out, reg.weight_decay);
+    out.push(reg.max_norm.is_some() as u8);
+    put_f32(out, reg.max_norm.unwrap_or(0.0));
+}
+
+fn write_conv<A>(out: &mut Vec<u8>, c: &Conv2d<A>, (id, param): (u8, Number))
+    where A: Activation<Number>
+{
+    let (ch, h, w) = c.input_shape();
+    out.push(CONV2D);
+    put_usizes(out,
+               &[ch, h, w, c.w.dim.0, c.kernel(), c.stride(), c.padding()]);
+    out.push(id);
+    put_f32(out, param);
+}
+
+fn put_pool(out: &mut Vec<u8>,
+            (c, h, w): (usize, usize, usize),
+            kernel: usize,
+            stride: usize)
+{
+    put_usizes(out, &[c, h, w, kernel, stride]);
+}
+
+fn read_module(r: &mut Reader) -> Result<Box<dyn Module>, ModelError>
+{
+    // nothing random survives loading, the params get overwritten
+    let mut rng = ::rng::seeded(0);
+    let kind = r.u8()?;
+    let mut m: Box<dyn Module> = match kind {
+        DENSE => {
+            let (into, out) = (r.usize()?, r.usize()?);
+            let act = read_act(r)?;
+            let dropout = r.f32()?;
+            let reg = Regularization { l2: r.f32()?,
+                                       l1: r.f32()?,
+                                       decay_bias: r.u8()? != 0,
+                                       weight_decay: r.f32()?,
+                                       max_norm: match (r.u8()?, r.f32()?) {
+                                           (0, _) => None,
+                                           (_, n) => Some(n),
+                                       } };
+            r.fits(&[into, out])?;
+            if !(0.0..1.0).contains(&dropout) {
+                return Err(ModelError::Malformed(format!("dropout {}",
+                                                         dropout)));
+            }
+            match act {
+                Some(act) => {
+                    let mut l = Layer::new_rand(act, into, out, &mut rng);
+                    l.set_regularization(reg);
+                    l.set_dropout(dropout);
+                    Box::new(l)
+                }
+                None => {
+                    let channels = r.peek_prelu_channels(2)?;
+                    if channels != 1 && channels != into {
+                        return Err(ModelError::Malformed(format!(
+                            "{} prelu slopes for {} inputs",
+                            channels, into
+                        )));
+                    }
+                    let prelu = PReLU::new(channels, 0.0);
+                    let mut l = Layer::new_rand(prelu, into, out, &mut rng);
+                    l.set_regularization(reg);
+                    l.set_dropout(dropout);
+                    Box::new(l)
+                }
+            }
+        }
+        CONV2D => {
+            let input = (r.usize()?, r.usize()?, r.usize()?);
+            let (out, k, stride, pad) =
+                (r.usize()?, r.usize()?, r.usize()?, r.usize()?);
+            check_window(input, k, stride, pad)?;
+            r.fits(&[out, input.0, k, k])?;
+            match read_act(r)? {
+                Some(act) => {
+                    Box::new(Conv2d::new_rand(act,
+                                              input,
+                                              out,
+                                              k,
+                                              stride,
+                                              pad,
+                                              &mut rng))
+                }
+                None => {
+                    let channels = r.peek_prelu_channels(2)?;
+                    let (c, h, w) = input;
+                    let into = c.saturating_mul(h).saturating_mul(w);
+                    if channels != 1 && channels != into {
+                        return Err(ModelError::Malformed(format!(
+                            "{} prelu slopes for {} inputs",
+                            channels, into
+                        )));
+                    }
+                    Box::new(Conv2d::new_rand(PReLU::new(channels, 0.0),
+                                              input,
+                                              out,
+                                              k,
+                                              stride,
+                                              pad,
+                                              &mut rng))
+                }
+            }
+        }
+        MAX_POOL2D | AVG_POOL2D => {
+            let input = (r.usize()?, r.usize()?, r.usize()?);
+            let (k, stride) = (r.usize()?, r.usize()?);
+            check_window(input, k, stride, 0)?;
+            if kind == MAX_POOL2D {
+                Box::new(MaxPool2d::new(input, k, stride))
+            } else {
+                Box::new(AvgPool2d::new(input, k, stride))
+            }
+        }
+        FLATTEN => Box::new(Flatten::new((r.usize()?, 1, 1))),
+        BATCH_NORM => {
+            let features = r.usize()?;
+            r.fits(&[features])?;
+            let mut bn = BatchNorm::new(features);
+            bn.momentum = r.f32()?;
+            bn.eps = r.f32()?;
+            Box::new(bn)
+        }
+        LAYER_NORM => {
+            let features = r.usize()?;
+            r.fits(&[features])?;
+            let mut ln = LayerNorm::new(features);
+            ln.eps = r.f32()?;
+            Box::new(ln)
+        }
+        _ => return Err(ModelError::Malformed(format!("module id {}", kind))),
+    };
+
+    let count = r.usize()?;
+    {
+        let mut params = m.params_and_grads();
+        if count != params.len() {
+            return Err(ModelError::Malformed(format!("{} params, expected {}",
+                                                     count,
+                                                     params.len())));
+        }
+        for (i, (p, _)) in params.iter_mut().enumerate() {
+            let loaded = r.matrix()?;
+            if loaded.dim != p.dim {
+                return Err(ModelError::Malformed(format!(
+                    "param {} is {:?}, expected {:?}",
+                    i, loaded.dim, p.dim
+                )));
+            }
+            **p = loaded;
+        }
+    }
+
+    let count = r.usize()?;
+    let expected = m.buffers();
+    if count != expected.len() {
+        return Err(ModelError::Malformed(format!("{} buffers, expected {}",
+                                                 count,
+                                                 expected.len())));
+    }
+    let mut buffers = Vec::with_capacity(count);
+    for (i, b) in expected.iter().enumerate() {
+        let loaded = r.matrix()?;
+        if loaded.dim != b.dim {
+            return Err(ModelError::Malformed(format!(
+                "buffer {} is {:?}, expected {:?}",
+                i, loaded.dim, b.dim
+            )));
+        }
+        buffers.push(loaded);
+    }
+    if count > 0 {
+        m.load_buffers(buffers);
+    }
+    Ok(m)
+}
+
+// the constructors assert on these, a file shouldn't be able to panic them.
+fn check_window(input: (usize, usize, usize),
+                k: usize,
+                stride: usize,
+                pad: usize)
+                -> Result<(), ModelError>
+{
+    let (_, h, w) = input;
+    if stride == 0 || k == 0 || h + 2 * pad < k || w + 2 * pad < k {
+        return Err(ModelError::Malformed(format!(
+            "a {}x{} kernel with stride {} on a {:?} input padded by {}",
+            k, k, stride, input, pad
+        )));
+    }
+    Ok(())
+}
+
+// crc-32 with the reflected 0xedb88320 polynomial, bit by bit. files are
+// small enough that a table isn't worth it.
+pub fn crc32(bytes: &[u8]) -> u32
+{
+    let mut crc = !0u32;
+    for &b in bytes {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            let mask = if crc & 1 == 1 { 0xedb8_8320 } else { 0 };
+            crc = (crc >> 1) ^ mask;
+        }
+    }
+    !crc
+}
+
+fn put_u32(out: &mut Vec<u8>, v: u32)
+{
+    out.extend_from_slice(&v.to_le_bytes());
+}
+
+fn put_usizes(out: &mut Vec<u8>, vs: &[usize])
+{
+    for &v in vs {
+        put_u32(out, v as u32);
+    }
+}
+
+fn put_f32(out: &mut Vec<u8>, v: Number)
+{
+    put_u32(out, v.to_bits());
+}
+
+fn put_matrix(out: &mut Vec<u8>, m: &Matrix<Number>)
+{
+    put_usizes(out, &[m.dim.0, m.dim.1]);
+    for &e in &m.a {
+        put_f32(out, e);
+    }
+}
+
+struct Reader<'a>
+{
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a>
+{
+    fn left(&self) -> usize
+    {
+        self.bytes.len() - self.pos
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], ModelError>
+    {
+        if n > self.left() {
+            return Err(ModelError::Truncated);
+        }
+        let bytes = &self.bytes[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, ModelError>
+    {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, ModelError>
+    {
+        let mut b = [0; 4];
+        b.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(b))
+    }
+
+    fn u64(&mut self) -> Result<u64, ModelError>
+    {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(b))
+    }
+
+    fn usize(&mut self) -> Result<usize, ModelError>
+    {
+        Ok(self.u32()? as usize)
+    }
+
+    fn f32(&mut self) -> Result<Number, ModelError>
+    {
+        Ok(Number::from_bits(self.u32()?))
+    }
+
+    // whether a matrix of the product of dims, none of them 0, could still
+    // be in the file. checked before building a module from the config, so
+    // a bad size can't ask for gigabytes.
+    fn fits(&self, dims: &[usize]) -> Result<(), ModelError>
+    {
+        if dims.contains(&0) {
+            return Err(ModelError::Malformed(format!("a size of 0 in {:?}",
+                                                     dims)));
+        }
+        let len = dims.iter().fold(4usize, |acc, d| acc.saturating_mul(*d));
+        if len > self.left() {
+            return Err(ModelError::Truncated);
+        }
+        Ok(())
+    }
+
+    fn matrix(&mut self) -> Result<Matrix<Number>, ModelError>
+    {
+        let (m, n) = (self.usize()?, self.usize()?);
+        if m.saturating_mul(n).saturating_mul(4) > self.left() {
+            return Err(ModelError::Truncated);
+        }
+        let mut a = Vec::with_capacity(m * n);
+        for _ in 0..m * n {
+            a.push(self.f32()?);
+        }
+        Ok(Matrix::with_vec((m, n), a))
+    }
+
+    // prelu layers are built before their params are read, but need to know
+    // how many slopes they have, which is the row count of the last param,
+    // the one after the weights and bias.
+    fn peek_prelu_channels(&self, skip: usize) -> Result<usize, ModelError>
+    {
+        let mut r = Reader { bytes: self.bytes, pos: self.pos };
+        if r.usize()? != skip + 1 {
+            return Err(ModelError::Malformed("prelu without slopes".into()));
+        }
+        for _ in 0..skip {
+            r.matrix()?;
+        }
+        let channels = r.usize()?;
+        if channels == 0 {
+            return Err(ModelError::Malformed("prelu with 0 slopes".into()));
+        }
+        Ok(channels)
+    }
+}
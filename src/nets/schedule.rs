@@ -0,0 +1,271 @@
+use nets::*;
+
+use std::f32::consts::PI;
+
+/*
+ * the learning rate as a function of how far training is. FFNet asks for
+ * the rate before every batch, counting batches from 0, and scales the step
+ * it was given (base) by it. schedules that react to how training is going
+ * get the validation loss through observe every time the net is tested.
+ */
+pub trait Schedule: Send + Sync
+{
+    fn name(&self) -> &'static str;
+
+    fn rate(&self, batch: usize, base: Number) -> Number;
+
+    fn observe(&mut self, _metric: Number) {}
+}
+
+pub struct Constant;
+
+impl Schedule for Constant
+{
+    fn name(&self) -> &'static str
+    {
+        "constant"
+    }
+
+    fn rate(&self, _batch: usize, base: Number) -> Number
+    {
+        base
+    }
+}
+
+// multiplies the rate by factor every so many batches.
+pub struct StepDecay
+{
+    pub every: usize,
+    pub factor: Number,
+}
+
+impl StepDecay
+{
+    pub fn new(every: usize, factor: Number) -> StepDecay
+    {
+        assert!(every > 0, "step decay needs every > 0");
+        StepDecay { every, factor }
+    }
+}
+
+impl Schedule for StepDecay
+{
+    fn name(&self) -> &'static str
+    {
+        "step_decay"
+    }
+
+    fn rate(&self, batch: usize, base: Number) -> Number
+    {
+        // every = 0 can only come from setting the field, and acts as 1
+        base * self.factor.powi((batch / self.every.max(1)) as i32)
+    }
+}
+
+// like StepDecay but smooth, base * gamma^(batch / every).
+pub struct Exponential
+{
+    pub every: usize,
+    pub gamma: Number,
+}
+
+impl Exponential
+{
+    pub fn new(every: usize, gamma: Number) -> Exponential
+    {
+        assert!(every > 0, "exponential decay needs every > 0");
+        Exponential { every, gamma }
+    }
+}
+
+impl Schedule for Exponential
+{
+    fn name(&self) -> &'static str
+    {
+        "exponential"
+    }
+
+    fn rate(&self, batch: usize, base: Number) -> Number
+    {
+        let every = self.every.max(1) as Number;
+        base * self.gamma.powf(batch as Number / every)
+    }
+}
+
+/*
+ * anneals from base down to min along half a cosine over period batches,
+ * then jumps back up to base. each period is mult times longer than the one
+ * before it, mult = 1 keeps them all the same.
+ */
+pub struct CosineRestarts
+{
+    pub period: usize,
+    pub mult: usize,
+    pub min: Number,
+}
+
+impl CosineRestarts
+{
+    pub fn new(period: usize, mult: usize, min: Number) -> CosineRestarts
+    {
+        assert!(period > 0, "cosine restarts needs period > 0");
+        CosineRestarts { period, mult, min }
+    }
+}
+
+impl Schedule for CosineRestarts
+{
+    fn name(&self) -> &'static str
+    {
+        "cosine_restarts"
+    }
+
+    fn rate(&self, batch: usize, base: Number) -> Number
+    {
+        let (mut t, mut period) = (batch, self.period.max(1));
+        while t >= period {
+            t -= period;
+            period *= self.mult.max(1);
+        }
+        let progress = t as Number / period as Number;
+        self.min + (base - self.min) * 0.5 * (1.0 + (PI * progress).cos())
+    }
+}
+
+// ramps linearly up to the rate of then over the first batches, then
+// follows then, starting from its batch 0.
+pub struct Warmup
+{
+    pub batches: usize,
+    pub then: Box<dyn Schedule>,
+}
+
+impl Warmup
+{
+    pub fn new(batches: usize, then: Box<dyn Schedule>) -> Warmup
+    {
+        Warmup { batches, then }
+    }
+}
+
+impl Schedule for Warmup
+{
+    fn name(&self) -> &'static str
+    {
+        "warmup"
+    }
+
+    fn rate(&self, batch: usize, base: Number) -> Number
+    {
+        if batch < self.batches {
+            let frac = (batch + 1) as Number / self.batches as Number;
+            frac * self.then.rate(0, base)
+        } else {
+            self.then.rate(batch - self.batches, base)
+        }
+    }
+
+    fn observe(&mut self, metric: Number)
+    {
+        self.then.observe(metric);
+    }
+}
+
+/*
+ * one cycle over total batches: rises linearly from base / div to base over
+ * the first warm fraction, then anneals along a cosine down to
+ * base / (div * final_div), and stays there.
+ */
+pub struct OneCycle
+{
+    pub total: usize,
+    pub warm: Number,
+    pub div: Number,
+    pub final_div: Number,
+}
+
+impl OneCycle
+{
+    pub fn new(total: usize) -> OneCycle
+    {
+        OneCycle { total, warm: 0.3, div: 25.0, final_div: 1e4 }
+    }
+}
+
+impl Schedule for OneCycle
+{
+    fn name(&self) -> &'static str
+    {
+        "one_cycle"
+    }
+
+    fn rate(&self, batch: usize, base: Number) -> Number
+    {
+        let start = base / self.div;
+        let end = start / self.final_div;
+        let up = (self.warm * self.total as Number).max(1.0);
+        let t = batch.min(self.total) as Number;
+
+        if t < up {
+            start + (base - start) * t / up
+        } else {
+            let down = (self.total as Number - up).max(1.0);
+            let progress = (t - up) / down;
+            end + (base - end) * 0.5 * (1.0 + (PI * progress).cos())
+        }
+    }
+}
+
+/*
+ * cuts the rate by factor whenever the validation loss hasn't improved on
+ * its best by more than threshold for patience observations in a row.
+ */
+pub struct ReduceOnPlateau
+{
+    pub factor: Number,
+    pub patience: usize,
+    pub threshold: Number,
+    best: Number,
+    bad: usize,
+    scale: Number,
+}
+
+impl ReduceOnPlateau
+{
+    pub fn new(factor: Number, patience: usize) -> ReduceOnPlateau
+    {
+        ReduceOnPlateau { factor,
+                          patience,
+                          threshold: 1e-4,
+                          best: Number::INFINITY,
+                          bad: 0,
+                          scale: 1.0 }
+    }
+}
+
+impl Schedule for ReduceOnPlateau
+{
+    fn name(&self) -> &'static str
+    {
+        "reduce_on_plateau"
+    }
+
+    fn rate(&self, _batch: usize, base: Number) -> Number
+    {
+        base * self.scale
+    }
+
+    fn observe(&mut self, metric: Number)
+    {
+        if metric < self.best - self.threshold {
+            self.best = metric;
+            self.bad = 0;
+        } else {
+            self.bad += 1;
+            if self.bad > self.patience {
+                self.scale *= self.factor;
+                self.bad = 0;
+            }
+        }
+    }
+}
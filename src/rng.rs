@@ -0,0 +1,28 @@
+extern crate rand;
+use self::rand::SeedableRng;
+pub use self::rand::StdRng;
+
+// everything random in the crate should draw from an rng made here, so that
+// a run can be replayed exactly from its seed.
+
+pub fn seeded(seed: usize) -> StdRng
+{
+    StdRng::from_seed(&[seed][..])
+}
+
+// an independent stream derived from a master seed, e.g. stream(seed, &[step,
+// thread]) gives each worker thread its own rng for a batch. the same seed
+// and ids always give the same stream, no matter how threads get scheduled.
+pub fn stream(seed: usize, ids: &[usize]) -> StdRng
+{
+    let mut key = Vec::with_capacity(ids.len() + 1);
+    key.push(seed);
+    key.extend_from_slice(ids);
+    StdRng::from_seed(&key[..])
+}
+
+// a fresh seed for when the caller doesn't care which one is used.
+pub fn random_seed() -> usize
+{
+    rand::random()
+}
@@ -0,0 +1,181 @@
+use std::ops::{Add, Mul, Sub};
+use std::cmp::PartialOrd;
+
+extern crate rand;
+
+use Matrix;
+
+// compressed sparse row storage. the nonzeros of row i are
+// vals[row_ptr[i]..row_ptr[i + 1]], sitting in the columns given by the same
+// range of cols.
+#[derive(Debug, Clone)]
+pub struct SparseMatrix<T>
+    where T: Mul<Output = T> + Add<Output = T> + Sub<Output = T> + Copy
+{
+    pub dim: (usize, usize),
+    pub vals: Vec<T>,
+    pub cols: Vec<usize>,
+    pub row_ptr: Vec<usize>,
+}
+
+impl<T> SparseMatrix<T>
+    where T: Mul<Output = T>
+        + Add<Output = T>
+        + Sub<Output = T>
+        + Copy
+        + Default
+        + PartialEq
+        + rand::distributions::range::SampleRange
+        + PartialOrd
+{
+    pub fn from_dense(m: &Matrix<T>) -> SparseMatrix<T>
+    {
+        let (rows, n) = m.dim;
+        let zero = T::default();
+
+        let mut vals = Vec::new();
+        let mut cols = Vec::new();
+        let mut row_ptr = Vec::with_capacity(rows + 1);
+        row_ptr.push(0);
+
+        for i in 0..rows {
+            for j in 0..n {
+                let e = m.a[i * n + j];
+                if e != zero {
+                    vals.push(e);
+                    cols.push(j);
+                }
+            }
+            row_ptr.push(vals.len());
+        }
+
+        SparseMatrix { dim: m.dim, vals, cols, row_ptr }
+    }
+
+    pub fn to_dense(&self) -> Matrix<T>
+    {
+        let n = self.dim.1;
+        let mut r = Matrix::new_const(self.dim, T::default());
+        for i in 0..self.dim.0 {
+            for k in self.row_ptr[i]..self.row_ptr[i + 1] {
+                r.a[i * n + self.cols[k]] = self.vals[k];
+            }
+        }
+        r
+    }
+
+    // number of stored (nonzero) entries
+    pub fn nnz(&self) -> usize
+    {
+        self.vals.len()
+    }
+
+    pub fn density(&self) -> f32
+    {
+        self.nnz() as f32 / (self.dim.0 * self.dim.1) as f32
+    }
+
+    // applies f to the stored entries only, so f(0) should be 0.
+    pub fn map_nonzero<F: Fn(T) -> T>(&self, f: F) -> SparseMatrix<T>
+    {
+        SparseMatrix { dim: self.dim,
+                       vals: self.vals.iter().map(|e| f(*e)).collect(),
+                       cols: self.cols.clone(),
+                       row_ptr: self.row_ptr.clone() }
+    }
+}
+
+impl<T> Matrix<T>
+    where T: Mul<Output = T>
+        + Add<Output = T>
+        + Sub<Output = T>
+        + Copy
+        + Default
+        + PartialEq
+        + rand::distributions::range::SampleRange
+        + PartialOrd
+{
+    // equivalent to: self * rhs
+    pub fn mul_sparse(&self, rhs: &SparseMatrix<T>) -> Matrix<T>
+    {
+        let (m, n) = self.dim;
+        let (m1, n1) = rhs.dim;
+
+        assert!(n == m1,
+                "can't do self*rhs with dimensions, {:?} and {:?}",
+                self.dim,
+                rhs.dim);
+
+        let mut r = Matrix::new_const((m, n1), T::default());
+
+        for k in 0..m1 {
+            for p in rhs.row_ptr[k]..rhs.row_ptr[k + 1] {
+                let (j, v) = (rhs.cols[p], rhs.vals[p]);
+                for i in 0..m {
+                    r.a[i * n1 + j] = r.a[i * n1 + j] + self.a[i * n + k] * v;
+                }
+            }
+        }
+        r
+    }
+
+    // equivalent to: self * (rhs^t)
+    pub fn mul_tr_sparse(&self, rhs: &SparseMatrix<T>) -> Matrix<T>
+    {
+        let (m, n) = self.dim;
+        let (m1, n1) = rhs.dim;
+
+        assert!(n == n1,
+                "can't do self*(rhs^t) with dimensions, {:?} and {:?}",
+                self.dim,
+                rhs.dim);
+
+        let mut r = Matrix::new((m, m1));
+
+        for i in 0..m {
+            for j in 0..m1 {
+                let mut acc = T::default();
+                for p in rhs.row_ptr[j]..rhs.row_ptr[j + 1] {
+                    acc = acc + self.a[i * n + rhs.cols[p]] * rhs.vals[p];
+                }
+                r.a.push(acc);
+            }
+        }
+        r
+    }
+}
+
+impl<'b, T> Mul<&'b Matrix<T>> for &SparseMatrix<T>
+    where T: Mul<Output = T>
+        + Add<Output = T>
+        + Sub<Output = T>
+        + Copy
+        + Default
+        + PartialEq
+        + rand::distributions::range::SampleRange
+        + PartialOrd
+{
+    type Output = Matrix<T>;
+
+    fn mul(self, rhs: &'b Matrix<T>) -> Matrix<T>
+    {
+        let (m, n) = self.dim;
+        let (m1, n1) = rhs.dim;
+
+        if n != m1 {
+            panic!("matrix dimensions are not compatable.");
+        }
+
+        let mut r = Matrix::new_const((m, n1), T::default());
+
+        for i in 0..m {
+            for p in self.row_ptr[i]..self.row_ptr[i + 1] {
+                let (k, v) = (self.cols[p], self.vals[p]);
+                for j in 0..n1 {
+                    r.a[i * n1 + j] = r.a[i * n1 + j] + v * rhs.a[k * n1 + j];
+                }
+            }
+        }
+        r
+    }
+}
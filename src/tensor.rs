@@ -0,0 +1,289 @@
+use std::ops::{Add, Index, Mul, Sub};
+use std::cmp::PartialOrd;
+
+extern crate rand;
+
+use Matrix;
+
+// an n dimensional array. element idx lives at data[sum(idx[k] * strides[k])],
+// so permuting and broadcasting only have to touch the shape and strides.
+// broadcast dimensions have a stride of 0.
+#[derive(Debug, Clone)]
+pub struct Tensor<T>
+    where T: Mul<Output = T> + Add<Output = T> + Sub<Output = T> + Copy
+{
+    pub shape: Vec<usize>,
+    pub strides: Vec<usize>,
+    pub data: Vec<T>,
+}
+
+// row major strides for a freshly laid out tensor of this shape.
+pub fn contiguous_strides(shape: &[usize]) -> Vec<usize>
+{
+    let mut strides = vec![1; shape.len()];
+    for k in (0..shape.len().saturating_sub(1)).rev() {
+        strides[k] = strides[k + 1] * shape[k + 1];
+    }
+    strides
+}
+
+// the shape two tensors broadcast to, lining dimensions up from the right.
+// None if some pair of dimensions differ and neither is 1.
+pub fn broadcast_shape(a: &[usize], b: &[usize]) -> Option<Vec<usize>>
+{
+    let rank = a.len().max(b.len());
+    let mut shape = vec![0; rank];
+    for k in 0..rank {
+        let da = if k < a.len() { a[a.len() - 1 - k] } else { 1 };
+        let db = if k < b.len() { b[b.len() - 1 - k] } else { 1 };
+        shape[rank - 1 - k] = if da == db || db == 1 {
+            da
+        } else if da == 1 {
+            db
+        } else {
+            return None;
+        };
+    }
+    Some(shape)
+}
+
+impl<T> Tensor<T>
+    where T: Mul<Output = T> + Add<Output = T> + Sub<Output = T> + Copy
+{
+    pub fn new(shape: Vec<usize>, data: Vec<T>) -> Tensor<T>
+    {
+        if shape.iter().product::<usize>() != data.len() {
+            panic!("attempted to make a tensor of shape {:?} from {} elements",
+                   shape,
+                   data.len());
+        }
+        let strides = contiguous_strides(&shape);
+        Tensor { shape, strides, data }
+    }
+
+    pub fn from_elem(shape: Vec<usize>, c: T) -> Tensor<T>
+    {
+        let len = shape.iter().product();
+        Tensor::new(shape, vec![c; len])
+    }
+
+    pub fn rank(&self) -> usize
+    {
+        self.shape.len()
+    }
+
+    // number of logical elements, which can be more than data.len() when
+    // broadcast.
+    pub fn len(&self) -> usize
+    {
+        self.shape.iter().product()
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.len() == 0
+    }
+
+    pub fn is_contiguous(&self) -> bool
+    {
+        self.strides == contiguous_strides(&self.shape)
+    }
+
+    fn offset(&self, idx: &[usize]) -> usize
+    {
+        assert!(idx.len() == self.rank(),
+                "index {:?} has the wrong rank for shape {:?}",
+                idx,
+                self.shape);
+
+        let mut off = 0;
+        for k in 0..idx.len() {
+            assert!(idx[k] < self.shape[k],
+                    "index {:?} out of bounds for shape {:?}",
+                    idx,
+                    self.shape);
+            off += idx[k] * self.strides[k];
+        }
+        off
+    }
+
+    // every element in row major order of the logical shape.
+    pub fn to_vec(&self) -> Vec<T>
+    {
+        if self.is_contiguous() {
+            return self.data.clone();
+        }
+
+        let len = self.len();
+        let mut v = Vec::with_capacity(len);
+        let mut idx = vec![0; self.rank()];
+        let mut off = 0;
+        for _ in 0..len {
+            v.push(self.data[off]);
+            // bump the index like an odometer, keeping the offset in step
+            for k in (0..idx.len()).rev() {
+                idx[k] += 1;
+                off += self.strides[k];
+                if idx[k] < self.shape[k] {
+                    break;
+                }
+                off -= idx[k] * self.strides[k];
+                idx[k] = 0;
+            }
+        }
+        v
+    }
+
+    pub fn contiguous(&self) -> Tensor<T>
+    {
+        Tensor::new(self.shape.clone(), self.to_vec())
+    }
+
+    pub fn reshape(&self, shape: Vec<usize>) -> Tensor<T>
+    {
+        assert!(shape.iter().product::<usize>() == self.len(),
+                "can't reshape {:?} into {:?}",
+                self.shape,
+                shape);
+        Tensor::new(shape, self.to_vec())
+    }
+
+    // reorders the dimensions, so permute(&[1, 0]) is a transpose.
+    pub fn permute(&self, axes: &[usize]) -> Tensor<T>
+    {
+        let mut seen = vec![false; self.rank()];
+        for &a in axes {
+            assert!(a < self.rank() && !seen[a],
+                    "{:?} is not a permutation of the axes of {:?}",
+                    axes,
+                    self.shape);
+            seen[a] = true;
+        }
+        assert!(axes.len() == self.rank(),
+                "{:?} is not a permutation of the axes of {:?}",
+                axes,
+                self.shape);
+
+        Tensor { shape: axes.iter().map(|&a| self.shape[a]).collect(),
+                 strides: axes.iter().map(|&a| self.strides[a]).collect(),
+                 data: self.data.clone() }
+    }
+
+    pub fn broadcast_to(&self, shape: &[usize]) -> Tensor<T>
+    {
+        assert!(shape.len() >= self.rank(),
+                "can't broadcast {:?} to {:?}",
+                self.shape,
+                shape);
+
+        let pad = shape.len() - self.rank();
+        let mut strides = vec![0; shape.len()];
+        for k in 0..self.rank() {
+            if self.shape[k] == shape[pad + k] {
+                strides[pad + k] = self.strides[k];
+            } else {
+                assert!(self.shape[k] == 1,
+                        "can't broadcast {:?} to {:?}",
+                        self.shape,
+                        shape);
+            }
+        }
+
+        Tensor { shape: shape.to_vec(), strides, data: self.data.clone() }
+    }
+
+    pub fn map<F: Fn(T) -> T>(&self, f: F) -> Tensor<T>
+    {
+        Tensor { shape: self.shape.clone(),
+                 strides: self.strides.clone(),
+                 data: self.data.iter().map(|e| f(*e)).collect() }
+    }
+
+    // applies f elementwise after broadcasting the two tensors together.
+    pub fn zip_with<F>(&self, rhs: &Tensor<T>, f: F) -> Tensor<T>
+        where F: Fn(T, T) -> T
+    {
+        let shape = match broadcast_shape(&self.shape, &rhs.shape) {
+            Some(shape) => shape,
+            None => panic!("tensor shapes {:?} and {:?} don't broadcast",
+                           self.shape,
+                           rhs.shape),
+        };
+        let a = self.broadcast_to(&shape).to_vec();
+        let b = rhs.broadcast_to(&shape).to_vec();
+
+        Tensor::new(shape,
+                    a.iter().zip(b.iter()).map(|(x, y)| f(*x, *y)).collect())
+    }
+}
+
+impl<T> Tensor<T>
+    where T: Mul<Output = T>
+        + Add<Output = T>
+        + Sub<Output = T>
+        + Copy
+        + rand::distributions::range::SampleRange
+        + PartialOrd
+{
+    pub fn from_matrix(m: &Matrix<T>) -> Tensor<T>
+    {
+        Tensor::new(vec![m.dim.0, m.dim.1], m.a.clone())
+    }
+
+    // rank 1 tensors become column vectors like the rest of the code expects.
+    pub fn to_matrix(&self) -> Matrix<T>
+    {
+        let dim = match self.shape.len() {
+            1 => (self.shape[0], 1),
+            2 => (self.shape[0], self.shape[1]),
+            _ => panic!("can't make a matrix from a tensor of shape {:?}",
+                        self.shape),
+        };
+        Matrix::with_vec(dim, self.to_vec())
+    }
+}
+
+impl<'b, T> Index<&'b [usize]> for Tensor<T>
+    where T: Mul<Output = T> + Add<Output = T> + Sub<Output = T> + Copy
+{
+    type Output = T;
+
+    fn index(&self, idx: &'b [usize]) -> &T
+    {
+        &self.data[self.offset(idx)]
+    }
+}
+
+impl<'b, T> Add<&'b Tensor<T>> for &Tensor<T>
+    where T: Mul<Output = T> + Add<Output = T> + Sub<Output = T> + Copy
+{
+    type Output = Tensor<T>;
+
+    fn add(self, rhs: &'b Tensor<T>) -> Tensor<T>
+    {
+        self.zip_with(rhs, |x, y| x + y)
+    }
+}
+
+impl<'b, T> Sub<&'b Tensor<T>> for &Tensor<T>
+    where T: Mul<Output = T> + Add<Output = T> + Sub<Output = T> + Copy
+{
+    type Output = Tensor<T>;
+
+    fn sub(self, rhs: &'b Tensor<T>) -> Tensor<T>
+    {
+        self.zip_with(rhs, |x, y| x - y)
+    }
+}
+
+// elementwise, like Matrix::h_prod but broadcasting.
+impl<'b, T> Mul<&'b Tensor<T>> for &Tensor<T>
+    where T: Mul<Output = T> + Add<Output = T> + Sub<Output = T> + Copy
+{
+    type Output = Tensor<T>;
+
+    fn mul(self, rhs: &'b Tensor<T>) -> Tensor<T>
+    {
+        self.zip_with(rhs, |x, y| x * y)
+    }
+}